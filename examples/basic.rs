@@ -140,8 +140,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Demonstrate range queries
     println!("\nRooms within ID range 1-2:");
-    let range_rooms: Vec<Room> =
-        db.get_range_cf("rooms", "1", "2", 1000, Direction::Forward, false)?;
+    let range_rooms: Vec<Room> = db.get_range_cf("rooms", "1"..="2", 1000, Direction::Forward)?;
     for room in range_rooms {
         println!("Room in range: {:?}", room);
     }
@@ -179,7 +178,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 // Helper function to filter rooms by style
 fn filter_rooms_by_style(db: &RocksDB, style: MatchStyle) -> Result<Vec<Room>, KvStoreError> {
-    let rooms: Vec<Room> = db.get_range_cf("rooms", "0", "999", 1000, Direction::Forward, true)?;
+    let rooms: Vec<Room> = db.get_range_cf("rooms", .., 1000, Direction::Forward)?;
     Ok(rooms
         .into_iter()
         .filter(|room| room.style == style)
@@ -215,7 +214,7 @@ fn print_cf_contents(db: &RocksDB) -> Result<(), KvStoreError> {
     // Print rooms
     println!("\nRooms:");
     print_cf_size("rooms")?;
-    let rooms: Vec<Room> = db.get_range_cf("rooms", "0", "999", 1000, Direction::Forward, false)?;
+    let rooms: Vec<Room> = db.get_range_cf("rooms", .., 1000, Direction::Forward)?;
     for room in rooms {
         println!("- {:?}", room);
     }
@@ -223,8 +222,7 @@ fn print_cf_contents(db: &RocksDB) -> Result<(), KvStoreError> {
     // Print settings
     println!("\nSettings:");
     print_cf_size("settings")?;
-    let settings: Vec<Settings> =
-        db.get_range_cf("settings", "0", "999", 1000, Direction::Forward, false)?;
+    let settings: Vec<Settings> = db.get_range_cf("settings", .., 1000, Direction::Forward)?;
     for setting in settings {
         println!("- {:?}", setting);
     }
@@ -232,14 +230,8 @@ fn print_cf_contents(db: &RocksDB) -> Result<(), KvStoreError> {
     // Print archived rooms
     println!("\nArchived Rooms:");
     print_cf_size("archived_rooms")?;
-    let archived_rooms: Vec<Room> = db.get_range_cf(
-        "archived_rooms",
-        "0",
-        "999",
-        1000,
-        Direction::Forward,
-        false,
-    )?;
+    let archived_rooms: Vec<Room> =
+        db.get_range_cf("archived_rooms", .., 1000, Direction::Forward)?;
     for room in archived_rooms {
         println!("- {:?}", room);
     }
@@ -1,5 +1,5 @@
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use rocksdb_client::{KVStore, KvStoreError, Options, RocksDB};
+use rocksdb_client::{AbortReason, KVStore, KvStoreError, Options, QueryContext, RocksDB};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -115,15 +115,16 @@ async fn run_query_benchmark(db: Arc<RocksDB>, num_rooms: usize) -> Result<(), K
         println!("{:-<50}", "");
 
         // Run values-only query
-        println!("\nRunning without keys (query_cf)...");
+        // The deadline is checked while scanning, so a slow query stops itself
+        let context = QueryContext::new().timeout(Duration::from_secs(QUERY_TIMEOUT_SECS));
+        println!("\nRunning without keys (query_cf_with_context)...");
         let start = Instant::now();
-        let query_result = tokio::time::timeout(Duration::from_secs(QUERY_TIMEOUT_SECS), async {
-            db.query_cf::<Room>("rooms", query)
-        })
-        .await;
+        let query_result = db
+            .query_cf_with_context::<Room>("rooms", query, &context)
+            .map(|outcome| outcome.items);
 
         match query_result {
-            Ok(Ok(matching_rooms)) => {
+            Ok(matching_rooms) => {
                 let duration = start.elapsed();
                 let result = QueryBenchmarkResults {
                     query_type: description.to_string(),
@@ -138,36 +139,36 @@ async fn run_query_benchmark(db: Arc<RocksDB>, num_rooms: usize) -> Result<(), K
                 );
                 value_only_results.push(result);
             }
-            Ok(Err(e)) => {
-                println!("Query error: {:?}", e);
+            Err(KvStoreError::QueryAborted(AbortReason::DeadlineExceeded)) => {
+                println!("Query timed out after {} seconds!", QUERY_TIMEOUT_SECS);
                 value_only_results.push(QueryBenchmarkResults {
-                    query_type: format!("{} (ERROR)", description),
+                    query_type: format!("{} (TIMEOUT)", description),
                     total_matches: 0,
                     duration: start.elapsed(),
                     matches_per_second: 0.0,
                 });
             }
-            Err(_) => {
-                println!("Query timed out after {} seconds!", QUERY_TIMEOUT_SECS);
+            Err(e) => {
+                println!("Query error: {:?}", e);
                 value_only_results.push(QueryBenchmarkResults {
-                    query_type: format!("{} (TIMEOUT)", description),
+                    query_type: format!("{} (ERROR)", description),
                     total_matches: 0,
-                    duration: Duration::from_secs(QUERY_TIMEOUT_SECS),
+                    duration: start.elapsed(),
                     matches_per_second: 0.0,
                 });
             }
         }
 
-        // Run key-value query
-        println!("\nRunning with keys (query_cf_with_keys)...");
+        // Run key-value query, splitting full scans across threads
+        let context = QueryContext::new().timeout(Duration::from_secs(QUERY_TIMEOUT_SECS));
+        println!("\nRunning with keys (par_query_cf_with_context)...");
         let start = Instant::now();
-        let query_result = tokio::time::timeout(Duration::from_secs(QUERY_TIMEOUT_SECS), async {
-            db.query_cf_with_keys::<Room>("rooms", query)
-        })
-        .await;
+        let query_result = db
+            .par_query_cf_with_context::<Room>("rooms", query, num_cpus::get(), &context)
+            .map(|outcome| outcome.items);
 
         match query_result {
-            Ok(Ok(matching_rooms)) => {
+            Ok(matching_rooms) => {
                 let duration = start.elapsed();
                 let result = QueryBenchmarkResults {
                     query_type: description.to_string(),
@@ -182,21 +183,21 @@ async fn run_query_benchmark(db: Arc<RocksDB>, num_rooms: usize) -> Result<(), K
                 );
                 key_value_results.push(result);
             }
-            Ok(Err(e)) => {
-                println!("Query error: {:?}", e);
+            Err(KvStoreError::QueryAborted(AbortReason::DeadlineExceeded)) => {
+                println!("Query timed out after {} seconds!", QUERY_TIMEOUT_SECS);
                 key_value_results.push(QueryBenchmarkResults {
-                    query_type: format!("{} (ERROR)", description),
+                    query_type: format!("{} (TIMEOUT)", description),
                     total_matches: 0,
                     duration: start.elapsed(),
                     matches_per_second: 0.0,
                 });
             }
-            Err(_) => {
-                println!("Query timed out after {} seconds!", QUERY_TIMEOUT_SECS);
+            Err(e) => {
+                println!("Query error: {:?}", e);
                 key_value_results.push(QueryBenchmarkResults {
-                    query_type: format!("{} (TIMEOUT)", description),
+                    query_type: format!("{} (ERROR)", description),
                     total_matches: 0,
-                    duration: Duration::from_secs(QUERY_TIMEOUT_SECS),
+                    duration: start.elapsed(),
                     matches_per_second: 0.0,
                 });
             }
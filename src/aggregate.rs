@@ -0,0 +1,219 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+
+use crate::context::QueryContext;
+use crate::errors::KvStoreError;
+use crate::query::{compare_values, field};
+
+#[derive(Debug, Clone)]
+enum Aggregate {
+    Count,
+    Sum(String),
+    Min(String),
+    Max(String),
+    Avg(String),
+}
+
+/// Aggregates computed by `aggregate_cf`, optionally over the documents
+/// matching a JSONPath filter and split into groups by a field. Each output
+/// is stored under its name, so a group deserializes into a struct with
+/// matching fields. Fields are dot-separated paths into the document.
+#[derive(Debug, Clone, Default)]
+pub struct Aggregation {
+    pub(crate) filter: Option<String>,
+    group_by: Option<String>,
+    outputs: Vec<(String, Aggregate)>,
+    pub(crate) context: QueryContext,
+}
+
+impl Aggregation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only aggregates documents matching this `$[?...]` query.
+    pub fn filter(mut self, query: &str) -> Self {
+        self.filter = Some(query.to_string());
+        self
+    }
+
+    /// Groups documents by the value of `field`. String values are used as
+    /// the group key as they are, other values as their JSON text; documents
+    /// without the field fall into the `null` group.
+    pub fn group_by(mut self, field: &str) -> Self {
+        self.group_by = Some(field.to_string());
+        self
+    }
+
+    /// Limits the scan with `context`. Aggregations stopping early fail with
+    /// `KvStoreError::QueryAborted`, even if partial results are allowed.
+    pub fn context(mut self, context: QueryContext) -> Self {
+        self.context = context;
+        self
+    }
+
+    pub fn count(self, name: &str) -> Self {
+        self.output(name, Aggregate::Count)
+    }
+
+    /// Sum of the numeric values of `field`; other values are ignored.
+    pub fn sum(self, name: &str, field: &str) -> Self {
+        self.output(name, Aggregate::Sum(field.to_string()))
+    }
+
+    /// Smallest value of `field`, ordered like `QueryOptions::order_by`.
+    pub fn min(self, name: &str, field: &str) -> Self {
+        self.output(name, Aggregate::Min(field.to_string()))
+    }
+
+    pub fn max(self, name: &str, field: &str) -> Self {
+        self.output(name, Aggregate::Max(field.to_string()))
+    }
+
+    /// Mean of the numeric values of `field`, or null if there are none.
+    pub fn avg(self, name: &str, field: &str) -> Self {
+        self.output(name, Aggregate::Avg(field.to_string()))
+    }
+
+    fn output(mut self, name: &str, aggregate: Aggregate) -> Self {
+        self.outputs.push((name.to_string(), aggregate));
+        self
+    }
+
+    pub(crate) fn accumulator(&self) -> Accumulator<'_> {
+        let mut groups = BTreeMap::new();
+        // Without grouping there is always a result, even for an empty CF
+        if self.group_by.is_none() {
+            groups.insert(String::new(), self.initial_states());
+        }
+        Accumulator {
+            aggregation: self,
+            groups,
+        }
+    }
+
+    fn initial_states(&self) -> Vec<State> {
+        self.outputs
+            .iter()
+            .map(|(_, aggregate)| match aggregate {
+                Aggregate::Count => State::Count(0),
+                Aggregate::Sum(_) => State::Sum {
+                    sum: 0.0,
+                    integral: true,
+                },
+                Aggregate::Min(_) => State::Min(None),
+                Aggregate::Max(_) => State::Max(None),
+                Aggregate::Avg(_) => State::Avg { sum: 0.0, count: 0 },
+            })
+            .collect()
+    }
+}
+
+enum State {
+    Count(u64),
+    // Sums of integers stay integers so they deserialize into integer fields
+    Sum { sum: f64, integral: bool },
+    Min(Option<Value>),
+    Max(Option<Value>),
+    Avg { sum: f64, count: u64 },
+}
+
+/// Running state of an aggregation, fed one document at a time.
+pub(crate) struct Accumulator<'a> {
+    aggregation: &'a Aggregation,
+    groups: BTreeMap<String, Vec<State>>,
+}
+
+impl Accumulator<'_> {
+    /// Fails for documents that aren't objects, such as structs MessagePack
+    /// wrote as positional arrays, whose fields can't be found by name.
+    pub(crate) fn add(&mut self, doc: &Value) -> Result<(), KvStoreError> {
+        if !doc.is_object() {
+            return Err(KvStoreError::DeserializationError(format!(
+                "aggregations need documents stored as maps, found {}; \
+                 values written as positional arrays can be converted with rewrite_cf",
+                doc
+            )));
+        }
+        let group = match &self.aggregation.group_by {
+            Some(path) => match field(doc, path) {
+                Some(Value::String(s)) => s.clone(),
+                Some(value) => value.to_string(),
+                None => Value::Null.to_string(),
+            },
+            None => String::new(),
+        };
+
+        let aggregation = self.aggregation;
+        let states = self
+            .groups
+            .entry(group)
+            .or_insert_with(|| aggregation.initial_states());
+        let outputs = &aggregation.outputs;
+
+        for ((_, aggregate), state) in outputs.iter().zip(states.iter_mut()) {
+            match (aggregate, state) {
+                (Aggregate::Count, State::Count(count)) => *count += 1,
+                (Aggregate::Sum(path), State::Sum { sum, integral }) => {
+                    if let Some(Value::Number(n)) = field(doc, path) {
+                        *sum += n.as_f64().unwrap_or(0.0);
+                        *integral &= !n.is_f64();
+                    }
+                }
+                (Aggregate::Min(path), State::Min(min)) => {
+                    keep_if(min, field(doc, path), Ordering::Less)
+                }
+                (Aggregate::Max(path), State::Max(max)) => {
+                    keep_if(max, field(doc, path), Ordering::Greater)
+                }
+                (Aggregate::Avg(path), State::Avg { sum, count }) => {
+                    if let Some(n) = field(doc, path).and_then(Value::as_f64) {
+                        *sum += n;
+                        *count += 1;
+                    }
+                }
+                _ => unreachable!("states are created from the same outputs"),
+            }
+        }
+        Ok(())
+    }
+
+    /// One JSON object per group, keyed by output name. An ungrouped
+    /// aggregation has a single group with an empty key.
+    pub(crate) fn finish(self) -> BTreeMap<String, Value> {
+        let outputs = &self.aggregation.outputs;
+        self.groups
+            .into_iter()
+            .map(|(group, states)| {
+                let mut object = Map::new();
+                for ((name, _), state) in outputs.iter().zip(states) {
+                    let value = match state {
+                        State::Count(count) => Value::from(count),
+                        State::Sum {
+                            sum,
+                            integral: true,
+                        } => Value::from(sum as i64),
+                        State::Sum { sum, .. } => Value::from(sum),
+                        State::Min(value) | State::Max(value) => value.unwrap_or(Value::Null),
+                        State::Avg { count: 0, .. } => Value::Null,
+                        State::Avg { sum, count } => Value::from(sum / count as f64),
+                    };
+                    object.insert(name.clone(), value);
+                }
+                (group, Value::Object(object))
+            })
+            .collect()
+    }
+}
+
+/// Replaces `current` with `candidate` if it compares as `wanted` to it.
+fn keep_if(current: &mut Option<Value>, candidate: Option<&Value>, wanted: Ordering) {
+    let Some(candidate) = candidate else {
+        return;
+    };
+    if current.is_none() || compare_values(Some(candidate), current.as_ref()) == wanted {
+        *current = Some(candidate.clone());
+    }
+}
@@ -0,0 +1,345 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use rocksdb::backup::{
+    BackupEngine, BackupEngineInfo, BackupEngineOptions, RestoreOptions as EngineRestoreOptions,
+};
+use rocksdb::{Env, IngestExternalFileOptions, LiveFile};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::errors::KvStoreError;
+use crate::RocksDB;
+
+/// A backup held by a `BackupStore`. `size` counts every file the backup
+/// uses, including the ones it shares with earlier backups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u32,
+    pub timestamp: i64,
+    pub size: u64,
+    pub num_files: u32,
+}
+
+impl From<BackupEngineInfo> for BackupInfo {
+    fn from(info: BackupEngineInfo) -> Self {
+        BackupInfo {
+            id: info.backup_id,
+            timestamp: info.timestamp,
+            size: info.size,
+            num_files: info.num_files,
+        }
+    }
+}
+
+/// A directory of whole-database backups managed by RocksDB's BackupEngine.
+/// Backups are incremental: SST files already backed up are shared rather
+/// than copied again.
+pub struct BackupStore {
+    engine: BackupEngine,
+    path: PathBuf,
+}
+
+impl BackupStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, KvStoreError> {
+        let opts = BackupEngineOptions::new(path.as_ref())?;
+        let env = Env::new()?;
+        Ok(BackupStore {
+            engine: BackupEngine::open(&opts, &env)?,
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Backs up every column family of `db`. The memtables are not flushed;
+    /// the WAL is copied instead, so all column families are captured at the
+    /// same sequence number.
+    pub fn create(&mut self, db: &RocksDB) -> Result<BackupInfo, KvStoreError> {
+        self.engine.create_new_backup(&db.db)?;
+        self.latest()
+            .ok_or_else(|| KvStoreError::IoError(io::Error::other("backup was not recorded")))
+    }
+
+    /// Backups from oldest to newest.
+    pub fn list(&self) -> Vec<BackupInfo> {
+        let mut backups: Vec<BackupInfo> = self
+            .engine
+            .get_backup_info()
+            .into_iter()
+            .map(BackupInfo::from)
+            .collect();
+        backups.sort_by_key(|backup| backup.id);
+        backups
+    }
+
+    pub fn latest(&self) -> Option<BackupInfo> {
+        self.list().pop()
+    }
+
+    /// Deletes all but the `keep` newest backups, along with the files only
+    /// they used.
+    pub fn purge(&mut self, keep: usize) -> Result<(), KvStoreError> {
+        self.engine.purge_old_backups(keep)?;
+        Ok(())
+    }
+
+    /// Checks that every file of the backup is present with the expected size.
+    pub fn verify(&self, id: u32) -> Result<(), KvStoreError> {
+        self.engine.verify_backup(id)?;
+        Ok(())
+    }
+
+    /// Restores backup `id`, or the latest one, into `path` so it can be
+    /// opened with `open_with_existing_cfs`. The directory must not hold any
+    /// files yet.
+    pub fn restore<P: AsRef<Path>>(
+        &mut self,
+        id: Option<u32>,
+        path: P,
+    ) -> Result<(), KvStoreError> {
+        let path = path.as_ref();
+        if path
+            .read_dir()
+            .is_ok_and(|mut entries| entries.next().is_some())
+        {
+            return Err(KvStoreError::IoError(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("restore target {} is not empty", path.display()),
+            )));
+        }
+
+        let opts = EngineRestoreOptions::default();
+        match id {
+            Some(id) => self.engine.restore_from_backup(path, path, &opts, id)?,
+            None => self.engine.restore_from_latest_backup(path, path, &opts)?,
+        }
+        Ok(())
+    }
+}
+
+/// Describes one SST file written by `create_backup` or `export_cfs`, so it
+/// can be checked before it is ingested.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub cf: String,
+    pub entries: u64,
+    /// Hex-encoded first and last keys, `None` for an empty column family.
+    pub first_key: Option<String>,
+    pub last_key: Option<String>,
+    /// Size of the SST file in bytes.
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// `Format::id` of the column family's serializer.
+    pub format: u8,
+    /// CRC-32 of the SST file.
+    pub checksum: u32,
+}
+
+impl BackupManifest {
+    /// Where `create_backup` puts the manifest of the SST file at `sst`.
+    pub fn path_for<P: AsRef<Path>>(sst: P) -> PathBuf {
+        let mut path = sst.as_ref().as_os_str().to_owned();
+        path.push(".manifest.json");
+        PathBuf::from(path)
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, KvStoreError> {
+        read_json(path.as_ref())
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), KvStoreError> {
+        write_json(path, self)
+    }
+
+    /// Checks that the file at `sst` has the recorded size and checksum. An
+    /// empty column family has no file to check. Restoring also checks the
+    /// entry count and key range, which needs the file ingested.
+    pub fn verify<P: AsRef<Path>>(&self, sst: P) -> Result<(), KvStoreError> {
+        if self.entries == 0 {
+            return Ok(());
+        }
+        let sst = sst.as_ref();
+        let size = fs::metadata(sst)?.len();
+        if size != self.size {
+            return Err(KvStoreError::InvalidBackup(format!(
+                "{} is {size} bytes, expected {}",
+                sst.display(),
+                self.size
+            )));
+        }
+        let checksum = checksum_file(sst)?;
+        if checksum != self.checksum {
+            return Err(KvStoreError::InvalidBackup(format!(
+                "{} has checksum {checksum:08x}, expected {:08x}",
+                sst.display(),
+                self.checksum
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks the entry count and key range RocksDB reports for the ingested
+    /// copy of the file against the recorded ones.
+    pub(crate) fn check_contents(&self, file: &LiveFile) -> Result<(), KvStoreError> {
+        if file.num_entries != self.entries {
+            return Err(KvStoreError::InvalidBackup(format!(
+                "backup of {} holds {} entries, expected {}",
+                self.cf, file.num_entries, self.entries
+            )));
+        }
+        let first_key = file.start_key.as_ref().map(hex::encode);
+        let last_key = file.end_key.as_ref().map(hex::encode);
+        if first_key != self.first_key || last_key != self.last_key {
+            return Err(KvStoreError::InvalidBackup(format!(
+                "backup of {} spans {first_key:?}..={last_key:?}, expected {:?}..={:?}",
+                self.cf, self.first_key, self.last_key
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Scratch column family SST files are ingested into by
+/// `RocksDB::check_sst_contents`.
+pub(crate) const CHECK_CF: &str = "__backup_check__";
+
+/// What `restore_backup_with` does with the target column family. Ingested
+/// files bypass index maintenance, so column families with indexes are
+/// refused in every mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestoreMode {
+    /// Ingests into the existing column family, overwriting keys it shares
+    /// with the backup and keeping the rest.
+    #[default]
+    Merge,
+    /// Drops the column family, recreates it empty with the same prefix
+    /// extractor and ingests. The backup is verified before anything is
+    /// dropped, but if ingesting still fails the old data is gone and the
+    /// column family is left empty. The default column family can't be
+    /// replaced.
+    Replace,
+    /// Creates the target column family, which must not exist yet. It may
+    /// have a different name than the one the backup was taken from.
+    IntoNew,
+}
+
+/// Options for `restore_backup_with`.
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    pub(crate) mode: RestoreMode,
+    pub(crate) move_files: bool,
+    pub(crate) allow_global_seqno: bool,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        RestoreOptions {
+            mode: RestoreMode::Merge,
+            move_files: false,
+            allow_global_seqno: true,
+        }
+    }
+}
+
+impl RestoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mode(mut self, mode: RestoreMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Hard-links the SST file into the database instead of copying it. The
+    /// backup file is gone afterwards, though its manifest is kept.
+    pub fn move_files(mut self, move_files: bool) -> Self {
+        self.move_files = move_files;
+        self
+    }
+
+    /// Whether RocksDB may assign the ingested keys a new sequence number
+    /// when they overlap data already in the column family. When disallowed,
+    /// such a restore fails instead. Allowed by default.
+    pub fn allow_global_seqno(mut self, allow: bool) -> Self {
+        self.allow_global_seqno = allow;
+        self
+    }
+
+    pub(crate) fn ingest_options(&self) -> IngestExternalFileOptions {
+        let mut opts = IngestExternalFileOptions::default();
+        opts.set_move_files(self.move_files);
+        opts.set_allow_global_seqno(self.allow_global_seqno);
+        opts
+    }
+}
+
+/// File `export_cfs` writes into the export directory once every SST file
+/// is complete.
+pub const EXPORT_MANIFEST: &str = "manifest.json";
+
+/// One column family of an export. `file` is relative to the export
+/// directory and is `None` when the column family was empty, since an SST
+/// file needs at least one entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CfExport {
+    pub file: Option<String>,
+    #[serde(flatten)]
+    pub manifest: BackupManifest,
+}
+
+/// Describes the SST files written by `export_cfs`. Every column family was
+/// read from the same snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportManifest {
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub column_families: Vec<CfExport>,
+}
+
+impl ExportManifest {
+    /// Reads the manifest of the export in `dir`.
+    pub fn read<P: AsRef<Path>>(dir: P) -> Result<Self, KvStoreError> {
+        read_json(&dir.as_ref().join(EXPORT_MANIFEST))
+    }
+
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), KvStoreError> {
+        write_json(&dir.join(EXPORT_MANIFEST), self)
+    }
+
+    pub fn get(&self, cf: &str) -> Option<&CfExport> {
+        self.column_families
+            .iter()
+            .find(|export| export.manifest.cf == cf)
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, KvStoreError> {
+    let bytes = fs::read(path)?;
+    serde_json::from_slice(&bytes).map_err(|e| KvStoreError::DeserializationError(e.to_string()))
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), KvStoreError> {
+    let bytes = serde_json::to_vec_pretty(value)
+        .map_err(|e| KvStoreError::SerializationError(e.to_string()))?;
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+/// CRC-32 (IEEE) of the file at `path`, read in chunks.
+pub(crate) fn checksum_file(path: &Path) -> Result<u32, KvStoreError> {
+    let mut file = fs::File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut hasher = crc32fast::Hasher::new();
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
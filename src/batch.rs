@@ -0,0 +1,122 @@
+use rocksdb::{WriteBatch, DEFAULT_COLUMN_FAMILY_NAME};
+use serde::Serialize;
+
+use crate::errors::KvStoreError;
+use crate::index::IndexedChange;
+use crate::serializer::ByteSerializer;
+use crate::{KVStore, RocksDB};
+
+/// Collects puts, deletes, range deletes and merges across column families and
+/// applies them atomically with a single write. Values go through the
+/// serializer configured for their column family. Puts and deletes keep
+/// secondary indexes up to date; range deletes and merges are rejected on
+/// indexed column families because their effect on documents is not known
+/// until they are applied.
+pub struct Batch<'a> {
+    db: &'a RocksDB,
+    batch: WriteBatch,
+    changes: Vec<IndexedChange>,
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new(db: &'a RocksDB) -> Self {
+        Batch {
+            db,
+            batch: WriteBatch::default(),
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn put<T: Serialize>(&mut self, key: &str, value: &T) -> Result<&mut Self, KvStoreError> {
+        self.put_cf(DEFAULT_COLUMN_FAMILY_NAME, key, value)
+    }
+
+    pub fn put_cf<T: Serialize>(
+        &mut self,
+        cf: &str,
+        key: &str,
+        value: &T,
+    ) -> Result<&mut Self, KvStoreError> {
+        let cf_handle = self.db.cf_handle(cf)?;
+        let serialized = self.db.serializers.for_cf(cf).serialize(value)?;
+        self.batch.put_cf(&cf_handle, key.as_bytes(), serialized);
+        if self.db.indexes.is_indexed(cf) {
+            let doc = serde_json::to_value(value)
+                .map_err(|e| KvStoreError::SerializationError(e.to_string()))?;
+            self.changes.push(IndexedChange {
+                cf: cf.to_string(),
+                key: key.to_string(),
+                doc: Some(doc),
+            });
+        }
+        Ok(self)
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<&mut Self, KvStoreError> {
+        self.delete_cf(DEFAULT_COLUMN_FAMILY_NAME, key)
+    }
+
+    pub fn delete_cf(&mut self, cf: &str, key: &str) -> Result<&mut Self, KvStoreError> {
+        let cf_handle = self.db.cf_handle(cf)?;
+        self.batch.delete_cf(&cf_handle, key.as_bytes());
+        if self.db.indexes.is_indexed(cf) {
+            self.changes.push(IndexedChange {
+                cf: cf.to_string(),
+                key: key.to_string(),
+                doc: None,
+            });
+        }
+        Ok(self)
+    }
+
+    /// Removes every key in `[from, to)`.
+    pub fn delete_range_cf(
+        &mut self,
+        cf: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<&mut Self, KvStoreError> {
+        self.reject_indexed(cf)?;
+        let cf_handle = self.db.cf_handle(cf)?;
+        self.batch
+            .delete_range_cf(&cf_handle, from.as_bytes(), to.as_bytes());
+        Ok(self)
+    }
+
+    /// Queues a merge operand; the column family must have a merge operator
+    /// configured or the write will fail.
+    pub fn merge_cf<T: Serialize>(
+        &mut self,
+        cf: &str,
+        key: &str,
+        value: &T,
+    ) -> Result<&mut Self, KvStoreError> {
+        self.reject_indexed(cf)?;
+        let cf_handle = self.db.cf_handle(cf)?;
+        let serialized = self.db.serializers.for_cf(cf).serialize(value)?;
+        self.batch.merge_cf(&cf_handle, key.as_bytes(), serialized);
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.batch.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    pub fn write(self) -> Result<(), KvStoreError> {
+        self.db.write_indexed(self.batch, self.changes)
+    }
+
+    fn reject_indexed(&self, cf: &str) -> Result<(), KvStoreError> {
+        if self.db.indexes.is_indexed(cf) {
+            return Err(KvStoreError::InvalidQuery(format!(
+                "{} has secondary indexes; use put_cf and delete_cf instead",
+                cf
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::errors::KvStoreError;
+use crate::serializer::ByteSerializer;
+
+// 0xC1 is never used by MessagePack or JSON, so with those inner formats
+// tagged values can't be confused with values written before compression was
+// enabled. Other formats may start with it, so their values must be tagged.
+const MAGIC: u8 = 0xC1;
+const TAG_STORED: u8 = 0x00;
+#[cfg(feature = "zstd")]
+const TAG_ZSTD: u8 = 0x01;
+#[cfg(feature = "lz4")]
+const TAG_LZ4: u8 = 0x02;
+
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Wraps another serializer and compresses its output once it grows past
+/// `threshold` bytes. Every value is prefixed with a two byte header, so reads
+/// pick the right decoder regardless of the current settings.
+///
+/// Values written before compression was enabled are still read when `inner`
+/// reserves the header's marker byte (MessagePack, JSON). For other formats
+/// compression has to be enabled before any value is written; untagged values
+/// fail to decode.
+#[derive(Debug, Clone, Copy)]
+pub struct Compressed<S> {
+    inner: S,
+    compression: Compression,
+    threshold: usize,
+}
+
+impl<S: ByteSerializer> Compressed<S> {
+    pub fn new(inner: S, compression: Compression) -> Self {
+        Compressed {
+            inner,
+            compression,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+}
+
+impl<S: ByteSerializer> ByteSerializer for Compressed<S> {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
+        let raw = self.inner.serialize(value)?;
+        if raw.len() < self.threshold {
+            let mut out = Vec::with_capacity(raw.len() + 2);
+            out.extend_from_slice(&[MAGIC, TAG_STORED]);
+            out.extend_from_slice(&raw);
+            return Ok(out);
+        }
+
+        let (tag, body) = match self.compression {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => (
+                TAG_ZSTD,
+                zstd::bulk::compress(&raw, level)
+                    .map_err(|e| KvStoreError::SerializationError(e.to_string()))?,
+            ),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => (TAG_LZ4, lz4_flex::compress_prepend_size(&raw)),
+        };
+
+        let mut out = Vec::with_capacity(body.len() + 2);
+        out.extend_from_slice(&[MAGIC, tag]);
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
+        match decompress(bytes)? {
+            Some(raw) => self.inner.deserialize(&raw),
+            None if self.inner.reserves_marker_byte() => self.inner.deserialize(bytes),
+            None => Err(KvStoreError::DeserializationError(
+                "value has no compression header".to_string(),
+            )),
+        }
+    }
+}
+
+/// Strips the compression header from `bytes`, returning `None` for values
+/// that were written without one.
+fn decompress(bytes: &[u8]) -> Result<Option<Vec<u8>>, KvStoreError> {
+    let (tag, body) = match bytes {
+        [MAGIC, tag, body @ ..] => (*tag, body),
+        _ => return Ok(None),
+    };
+
+    match tag {
+        TAG_STORED => Ok(Some(body.to_vec())),
+        #[cfg(feature = "zstd")]
+        TAG_ZSTD => zstd::stream::decode_all(body)
+            .map(Some)
+            .map_err(|e| KvStoreError::DeserializationError(e.to_string())),
+        #[cfg(feature = "lz4")]
+        TAG_LZ4 => lz4_flex::decompress_size_prepended(body)
+            .map(Some)
+            .map_err(|e| KvStoreError::DeserializationError(e.to_string())),
+        _ => Err(KvStoreError::DeserializationError(format!(
+            "unsupported compression tag: {:#04x}",
+            tag
+        ))),
+    }
+}
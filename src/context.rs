@@ -0,0 +1,137 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::KvStoreError;
+use crate::KeyValuePair;
+
+/// Shared flag for stopping a running query from another thread or task.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Why a query stopped before scanning everything it needed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    DeadlineExceeded,
+    RowLimitReached,
+    Cancelled,
+}
+
+impl fmt::Display for AbortReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbortReason::DeadlineExceeded => write!(f, "deadline exceeded"),
+            AbortReason::RowLimitReached => write!(f, "scanned row limit reached"),
+            AbortReason::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Limits checked before every row a query reads. A default context never
+/// aborts.
+#[derive(Debug, Clone, Default)]
+pub struct QueryContext {
+    deadline: Option<Instant>,
+    max_scanned_rows: Option<u64>,
+    cancellation: Option<CancellationToken>,
+    pub(crate) partial: bool,
+}
+
+impl QueryContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the deadline `timeout` from now.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.deadline(Instant::now() + timeout)
+    }
+
+    pub fn max_scanned_rows(mut self, rows: u64) -> Self {
+        self.max_scanned_rows = Some(rows);
+        self
+    }
+
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Returns the matches found so far when the query is aborted, instead of
+    /// `KvStoreError::QueryAborted`.
+    pub fn allow_partial(mut self) -> Self {
+        self.partial = true;
+        self
+    }
+
+    /// Fails once `scanned` rows exceed the budget, the deadline has passed
+    /// or the query was cancelled.
+    pub(crate) fn check(&self, scanned: u64) -> Result<(), KvStoreError> {
+        let reason = if self
+            .cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+        {
+            AbortReason::Cancelled
+        } else if self.max_scanned_rows.is_some_and(|max| scanned > max) {
+            AbortReason::RowLimitReached
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            AbortReason::DeadlineExceeded
+        } else {
+            return Ok(());
+        };
+        Err(KvStoreError::QueryAborted(reason))
+    }
+
+    /// Turns the result of a scan into its outcome, keeping `items` when the
+    /// scan was aborted and partial results are allowed.
+    pub(crate) fn outcome<T>(
+        &self,
+        scan: Result<(), KvStoreError>,
+        items: Vec<KeyValuePair<T>>,
+    ) -> Result<QueryOutcome<T>, KvStoreError> {
+        match scan {
+            Ok(()) => Ok(QueryOutcome {
+                items,
+                aborted: None,
+            }),
+            Err(KvStoreError::QueryAborted(reason)) if self.partial => Ok(QueryOutcome {
+                items,
+                aborted: Some(reason),
+            }),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Matches of a query run with a `QueryContext`. `aborted` is set when the
+/// context allowed partial results and the query stopped early.
+pub struct QueryOutcome<T> {
+    pub items: Vec<KeyValuePair<T>>,
+    pub aborted: Option<AbortReason>,
+}
@@ -3,6 +3,8 @@ use std::string::FromUtf8Error;
 use std::time::SystemTimeError;
 use thiserror::Error;
 
+use crate::context::AbortReason;
+
 #[derive(Error, Debug)]
 pub enum KvStoreError {
     #[error("Database operation failed: {0}")]
@@ -25,6 +27,14 @@ pub enum KvStoreError {
     PropertyAccessError(String),
     #[error("Query error: {0}")]
     InvalidQuery(String),
+    #[error("Schema migration error: {0}")]
+    MigrationError(String),
+    #[error("Transaction conflict: {0}")]
+    TransactionConflict(String),
+    #[error("Query aborted: {0}")]
+    QueryAborted(AbortReason),
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
 }
 
 impl From<rmp_serde::encode::Error> for KvStoreError {
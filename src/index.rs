@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+
+use jsonpath_rust::parser::model::JpQuery;
+use jsonpath_rust::parser::parse_json_path;
+use jsonpath_rust::query::js_path_process;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::errors::KvStoreError;
+use crate::range::{prefix_successor, KeyRange};
+
+/// Companion column families holding index entries are named
+/// `__index__<cf>__<index>`.
+pub(crate) const INDEX_CF_PREFIX: &str = "__index__";
+
+// Every index column family holds three kinds of keys:
+//   ENTRY   + encoded value + primary key -> empty, ordered for lookups
+//   REVERSE + primary key -> the encoded values currently indexed for it,
+//             so stale entries can be found without decoding the old document
+//   DEFINITION_KEY -> the index definition, written once the index is built
+//             so it can be registered again when the database is reopened
+const ENTRY: u8 = 0x00;
+const REVERSE: u8 = 0x01;
+pub(crate) const DEFINITION_KEY: &[u8] = &[0x02];
+
+// Tags keep values of different JSON types apart and ordered by type
+const NULL: u8 = 0x01;
+const FALSE: u8 = 0x02;
+const TRUE: u8 = 0x03;
+const NUMBER: u8 = 0x04;
+const STRING: u8 = 0x05;
+
+/// A secondary index over the scalar values a JSONPath selects from each
+/// document. Arrays are indexed element by element; objects are skipped.
+/// Writers maintain an index as soon as it is registered, but queries only
+/// use it once it is built.
+#[derive(Debug)]
+pub(crate) struct Index {
+    definition: Definition,
+    path: JpQuery,
+    cf_name: String,
+    built: AtomicBool,
+}
+
+/// What is stored under `DEFINITION_KEY`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Definition {
+    cf: String,
+    name: String,
+    path: String,
+}
+
+impl Index {
+    pub(crate) fn new(cf: &str, name: &str, path: &str) -> Result<Self, KvStoreError> {
+        let parsed = parse_json_path(path)
+            .map_err(|e| KvStoreError::InvalidQuery(format!("JSONPath error: {}", e)))?;
+        Ok(Index {
+            definition: Definition {
+                cf: cf.to_string(),
+                name: name.to_string(),
+                path: path.to_string(),
+            },
+            path: parsed,
+            cf_name: format!("{}{}__{}", INDEX_CF_PREFIX, cf, name),
+            built: AtomicBool::new(false),
+        })
+    }
+
+    /// Reads an index back from the definition stored in its column family.
+    /// The definition is only written once the index is built.
+    pub(crate) fn from_definition(bytes: &[u8]) -> Result<Self, KvStoreError> {
+        let definition: Definition = serde_json::from_slice(bytes)
+            .map_err(|e| KvStoreError::DeserializationError(e.to_string()))?;
+        let index = Index::new(&definition.cf, &definition.name, &definition.path)?;
+        index.mark_built();
+        Ok(index)
+    }
+
+    pub(crate) fn definition(&self) -> Result<Vec<u8>, KvStoreError> {
+        serde_json::to_vec(&self.definition)
+            .map_err(|e| KvStoreError::SerializationError(e.to_string()))
+    }
+
+    pub(crate) fn is_built(&self) -> bool {
+        self.built.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn mark_built(&self) {
+        self.built.store(true, Ordering::Release);
+    }
+
+    /// The column family this index covers.
+    pub(crate) fn cf(&self) -> &str {
+        &self.definition.cf
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.definition.name
+    }
+
+    pub(crate) fn path(&self) -> &JpQuery {
+        &self.path
+    }
+
+    pub(crate) fn cf_name(&self) -> &str {
+        &self.cf_name
+    }
+
+    /// The encoded values `doc` is indexed under, sorted and without duplicates.
+    pub(crate) fn values(&self, doc: &Value) -> Vec<Vec<u8>> {
+        let mut values = Vec::new();
+        for matched in js_path_process(&self.path, doc).unwrap_or_default() {
+            match matched.val() {
+                Value::Array(items) => values.extend(items.iter().filter_map(encode_value)),
+                other => values.extend(encode_value(other)),
+            }
+        }
+        values.sort();
+        values.dedup();
+        values
+    }
+}
+
+pub(crate) fn entry_key(value: &[u8], primary_key: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + value.len() + primary_key.len());
+    key.push(ENTRY);
+    key.extend_from_slice(value);
+    key.extend_from_slice(primary_key);
+    key
+}
+
+pub(crate) fn reverse_key(primary_key: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + primary_key.len());
+    key.push(REVERSE);
+    key.extend_from_slice(primary_key);
+    key
+}
+
+/// The primary key an index entry points at.
+pub(crate) fn primary_key(entry: &[u8]) -> Option<&[u8]> {
+    let value = entry.strip_prefix(&[ENTRY])?;
+    Some(&value[encoded_len(value)?..])
+}
+
+/// Splits a reverse entry back into the encoded values it lists.
+pub(crate) fn split_values(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut values = Vec::new();
+    while let Some(len) = encoded_len(bytes) {
+        values.push(bytes[..len].to_vec());
+        bytes = &bytes[len..];
+    }
+    values
+}
+
+/// Index entries whose value falls in `range`.
+pub(crate) fn entry_range<V: Serialize, R: RangeBounds<V>>(
+    range: &R,
+) -> Result<KeyRange, KvStoreError> {
+    let start = match range.start_bound() {
+        Bound::Included(v) => Bound::Included(entry_key(&encode_bound(v)?, &[])),
+        Bound::Excluded(v) => match prefix_successor(&entry_key(&encode_bound(v)?, &[])) {
+            Some(next) => Bound::Included(next),
+            None => Bound::Excluded(vec![REVERSE]),
+        },
+        Bound::Unbounded => Bound::Included(vec![ENTRY]),
+    };
+    let end = match range.end_bound() {
+        Bound::Included(v) => match prefix_successor(&entry_key(&encode_bound(v)?, &[])) {
+            Some(next) => Bound::Excluded(next),
+            None => Bound::Excluded(vec![REVERSE]),
+        },
+        Bound::Excluded(v) => Bound::Excluded(entry_key(&encode_bound(v)?, &[])),
+        Bound::Unbounded => Bound::Excluded(vec![REVERSE]),
+    };
+    Ok(KeyRange { start, end })
+}
+
+fn encode_bound<V: Serialize>(value: &V) -> Result<Vec<u8>, KvStoreError> {
+    let value =
+        serde_json::to_value(value).map_err(|e| KvStoreError::SerializationError(e.to_string()))?;
+    encode_value(&value)
+        .ok_or_else(|| KvStoreError::InvalidQuery("index lookups need a scalar value".to_string()))
+}
+
+/// Order-preserving encoding of scalar JSON values. Every encoding is
+/// self-delimiting, so a primary key can follow it directly. Numbers compare
+/// as `f64`.
+fn encode_value(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Null => Some(vec![NULL]),
+        Value::Bool(false) => Some(vec![FALSE]),
+        Value::Bool(true) => Some(vec![TRUE]),
+        Value::Number(n) => {
+            // -0.0 and 0.0 must encode the same
+            let n = n.as_f64()? + 0.0;
+            let bits = n.to_bits();
+            let ordered = if bits >> 63 == 1 {
+                !bits
+            } else {
+                bits | (1 << 63)
+            };
+            let mut out = vec![NUMBER];
+            out.extend_from_slice(&ordered.to_be_bytes());
+            Some(out)
+        }
+        Value::String(s) => {
+            // 0x00 is escaped as 0x00 0xFF and the string ends with 0x00 0x01
+            let mut out = vec![STRING];
+            for &b in s.as_bytes() {
+                out.push(b);
+                if b == 0x00 {
+                    out.push(0xFF);
+                }
+            }
+            out.extend_from_slice(&[0x00, 0x01]);
+            Some(out)
+        }
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+fn encoded_len(bytes: &[u8]) -> Option<usize> {
+    match *bytes.first()? {
+        NULL | FALSE | TRUE => Some(1),
+        NUMBER => (bytes.len() >= 9).then_some(9),
+        STRING => {
+            let mut i = 1;
+            while i + 1 < bytes.len() {
+                match (bytes[i], bytes[i + 1]) {
+                    (0x00, 0x01) => return Some(i + 2),
+                    (0x00, _) => i += 2,
+                    _ => i += 1,
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Indexes declared per column family. Writes to indexed column families are
+/// serialized through `write_lock` so that reading the current index entries
+/// and replacing them happens atomically.
+#[derive(Clone, Default)]
+pub(crate) struct IndexRegistry {
+    indexes: Arc<RwLock<HashMap<String, Vec<Arc<Index>>>>>,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl IndexRegistry {
+    pub(crate) fn for_cf(&self, cf: &str) -> Vec<Arc<Index>> {
+        self.indexes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cf)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn is_indexed(&self, cf: &str) -> bool {
+        self.indexes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cf)
+            .is_some_and(|indexes| !indexes.is_empty())
+    }
+
+    pub(crate) fn get(&self, cf: &str, name: &str) -> Option<Arc<Index>> {
+        self.for_cf(cf)
+            .into_iter()
+            .find(|index| index.name() == name)
+    }
+
+    pub(crate) fn add(&self, cf: &str, index: Index) {
+        let mut indexes = self.indexes.write().unwrap_or_else(PoisonError::into_inner);
+        let cf_indexes = indexes.entry(cf.to_string()).or_default();
+        cf_indexes.retain(|existing| existing.name() != index.name());
+        cf_indexes.push(Arc::new(index));
+    }
+
+    pub(crate) fn remove(&self, cf: &str, name: &str) -> Option<Arc<Index>> {
+        let mut indexes = self.indexes.write().unwrap_or_else(PoisonError::into_inner);
+        let cf_indexes = indexes.get_mut(cf)?;
+        let pos = cf_indexes.iter().position(|index| index.name() == name)?;
+        Some(cf_indexes.remove(pos))
+    }
+
+    pub(crate) fn remove_cf(&self, cf: &str) -> Vec<Arc<Index>> {
+        self.indexes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(cf)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, ()> {
+        self.write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A document written to an indexed column family; `None` means deleted.
+pub(crate) struct IndexedChange {
+    pub(crate) cf: String,
+    pub(crate) key: String,
+    pub(crate) doc: Option<Value>,
+}
@@ -0,0 +1,123 @@
+use std::marker::PhantomData;
+
+use rocksdb::{DBIteratorWithThreadMode, Direction, ReadOptions, DB};
+use serde::de::DeserializeOwned;
+
+use crate::errors::KvStoreError;
+use crate::key_filter::KeyFilter;
+use crate::range::KeyRange;
+use crate::schema::CfSerializer;
+use crate::serializer::ByteSerializer;
+use crate::KeyValuePair;
+
+type RawEntry = (Box<[u8]>, Box<[u8]>);
+
+/// Lazily walks a column family, deserializing each value only when it is
+/// yielded. `skip` and `nth` step over entries without decoding them.
+pub struct TypedIter<'a, T> {
+    inner: DBIteratorWithThreadMode<'a, DB>,
+    range: KeyRange,
+    key_filter: Option<KeyFilter>,
+    serializer: CfSerializer,
+    done: bool,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T: DeserializeOwned> TypedIter<'a, T> {
+    pub(crate) fn new(
+        db: &'a DB,
+        cf_handle: &impl rocksdb::AsColumnFamilyRef,
+        range: KeyRange,
+        opts: ReadOptions,
+        direction: Direction,
+        serializer: CfSerializer,
+    ) -> Self {
+        let inner = db.iterator_cf_opt(cf_handle, opts, range.iterator_mode(direction));
+        TypedIter {
+            inner,
+            range,
+            key_filter: None,
+            serializer,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Skips keys not matching `filter` without decoding their values.
+    pub(crate) fn filter_keys(mut self, filter: KeyFilter) -> Self {
+        self.key_filter = Some(filter);
+        self
+    }
+
+    /// Next raw key with its value, skipping keys outside the range or
+    /// rejected by the key filter. The iterator bounds are inclusive below, so
+    /// only an excluded start key can fall outside the range here.
+    pub(crate) fn next_raw(&mut self) -> Option<Result<RawEntry, KvStoreError>> {
+        if self.done {
+            return None;
+        }
+        let accepts = |key: &[u8]| {
+            self.range.contains(key)
+                && self
+                    .key_filter
+                    .as_ref()
+                    .is_none_or(|filter| filter.matches(key))
+        };
+        for item in self.inner.by_ref() {
+            match item {
+                Ok((key, value)) if accepts(&key) => return Some(Ok((key, value))),
+                Ok(_) => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        self.done = true;
+        None
+    }
+
+    pub(crate) fn serializer(&self) -> &CfSerializer {
+        &self.serializer
+    }
+
+    /// Decodes an entry returned by `next_raw`.
+    pub(crate) fn decode(&self, key: &[u8], value: &[u8]) -> Result<KeyValuePair<T>, KvStoreError> {
+        Ok(KeyValuePair {
+            key: String::from_utf8_lossy(key).into_owned(),
+            value: self.serializer.deserialize(value)?,
+        })
+    }
+
+    /// Like `next`, but keeps the raw key bytes.
+    pub(crate) fn next_entry(&mut self) -> Option<Result<(Vec<u8>, T), KvStoreError>> {
+        self.next_raw().map(|item| {
+            let (key, value) = item?;
+            let value = self.serializer.deserialize(&value)?;
+            Ok((key.into_vec(), value))
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for TypedIter<'_, T> {
+    type Item = Result<KeyValuePair<T>, KvStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|item| {
+            item.map(|(key, value)| KeyValuePair {
+                key: String::from_utf8_lossy(&key).into_owned(),
+                value,
+            })
+        })
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        for _ in 0..n {
+            match self.next_raw()? {
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.next()
+    }
+}
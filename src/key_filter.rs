@@ -0,0 +1,115 @@
+use regex::bytes::Regex;
+
+use crate::errors::KvStoreError;
+use crate::range::KeyRange;
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Prefix(String),
+    Glob(String),
+    Regex(Regex),
+    Range(KeyRange),
+}
+
+/// Restricts a query to keys matching a pattern. Keys are checked before
+/// their values are read, so documents under other keys are never
+/// deserialized.
+#[derive(Debug, Clone)]
+pub struct KeyFilter {
+    pattern: Pattern,
+}
+
+impl KeyFilter {
+    pub fn prefix(prefix: &str) -> Self {
+        KeyFilter {
+            pattern: Pattern::Prefix(prefix.to_string()),
+        }
+    }
+
+    /// `*` matches any run of characters and `?` any single character.
+    pub fn glob(pattern: &str) -> Self {
+        KeyFilter {
+            pattern: Pattern::Glob(pattern.to_string()),
+        }
+    }
+
+    /// Keys matching `pattern` anywhere; anchor it with `^` and `$` to match
+    /// whole keys.
+    pub fn regex(pattern: &str) -> Result<Self, KvStoreError> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| KvStoreError::InvalidQuery(format!("key regex error: {}", e)))?;
+        Ok(KeyFilter {
+            pattern: Pattern::Regex(regex),
+        })
+    }
+
+    pub fn range<R: Into<KeyRange>>(range: R) -> Self {
+        KeyFilter {
+            pattern: Pattern::Range(range.into()),
+        }
+    }
+
+    pub(crate) fn matches(&self, key: &[u8]) -> bool {
+        match &self.pattern {
+            Pattern::Prefix(prefix) => key.starts_with(prefix.as_bytes()),
+            Pattern::Glob(pattern) => std::str::from_utf8(key)
+                .is_ok_and(|key| glob_matches(pattern.as_bytes(), key.as_bytes())),
+            Pattern::Regex(regex) => regex.is_match(key),
+            Pattern::Range(range) => range.contains(key),
+        }
+    }
+
+    /// The narrowest key range holding every matching key.
+    pub(crate) fn scan_range(&self) -> KeyRange {
+        match &self.pattern {
+            Pattern::Prefix(prefix) => KeyRange::prefix(prefix),
+            Pattern::Glob(pattern) => {
+                let literal = pattern.find(['*', '?']).unwrap_or(pattern.len());
+                KeyRange::prefix(&pattern[..literal])
+            }
+            Pattern::Regex(_) => KeyRange::all(),
+            Pattern::Range(range) => range.clone(),
+        }
+    }
+}
+
+/// Glob matching with backtracking to the last `*`. `?` consumes one whole
+/// UTF-8 character.
+fn glob_matches(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(b'?') => {
+                p += 1;
+                k += char_len(key[k]);
+            }
+            Some(&c) if c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match star {
+                // Let the last `*` swallow one more character and retry
+                Some((star_p, star_k)) => {
+                    let next = star_k + char_len(key[star_k]);
+                    star = Some((star_p, next));
+                    p = star_p + 1;
+                    k = next;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn char_len(first: u8) -> usize {
+    match first.leading_ones() {
+        0 => 1,
+        n => n as usize,
+    }
+}
@@ -1,13 +1,68 @@
 use std::path::Path;
 
-use jsonpath_rust::JsonPath;
-pub use rocksdb::{ColumnFamilyDescriptor, CuckooTableOptions, Direction, Options};
-use rocksdb::{IngestExternalFileOptions, IteratorMode, SstFileWriter, WriteBatch, DB};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::Snapshot;
+pub use rocksdb::{
+    ColumnFamilyDescriptor, CuckooTableOptions, Direction, Options, TransactionDBOptions,
+};
+use rocksdb::{
+    IteratorMode, ReadOptions, SstFileWriter, WriteBatch, DB, DEFAULT_COLUMN_FAMILY_NAME,
+};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+pub mod aggregate;
+pub mod backup;
+pub mod batch;
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+pub mod compression;
+pub mod context;
 pub mod errors;
-use std::sync::Arc;
-
+mod index;
+pub mod iter;
+pub mod key_filter;
+mod msgpack;
+pub mod pagination;
+pub mod predicate;
+pub mod prefix;
+pub mod query;
+pub mod range;
+pub mod schema;
+pub mod serializer;
+pub mod transaction;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use aggregate::Aggregation;
+pub use backup::{
+    BackupInfo, BackupManifest, BackupStore, CfExport, ExportManifest, RestoreMode, RestoreOptions,
+};
+pub use batch::Batch;
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+pub use compression::{Compressed, Compression};
+pub use context::{AbortReason, CancellationToken, QueryContext, QueryOutcome};
 pub use errors::KvStoreError;
+use index::{Index, IndexRegistry, IndexedChange};
+pub use iter::TypedIter;
+pub use key_filter::KeyFilter;
+pub use pagination::{Page, PageToken};
+pub use predicate::{field, Field, Predicate};
+pub use prefix::PrefixExtractor;
+use prefix::PREFIX_CF;
+use query::PlannedQuery;
+pub use query::{QueryOptions, QueryPlan, SortOrder};
+pub use range::KeyRange;
+pub use schema::Schema;
+#[cfg(feature = "bincode")]
+pub use serializer::BincodeSerializer;
+#[cfg(feature = "cbor")]
+pub use serializer::CborSerializer;
+#[cfg(feature = "json")]
+pub use serializer::JsonSerializer;
+use serializer::SerializerRegistry;
+pub use serializer::{ByteSerializer, Format, MessagePackSerializer};
+pub use transaction::{Transaction, TransactionalRocksDB};
 
 #[derive(Serialize, Deserialize)]
 pub struct KeyValuePair<T> {
@@ -15,22 +70,6 @@ pub struct KeyValuePair<T> {
     pub value: T,
 }
 
-pub trait ByteSerializer {
-    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError>;
-    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError>;
-}
-
-pub struct MessagePackSerializer;
-
-impl ByteSerializer for MessagePackSerializer {
-    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
-        rmp_serde::to_vec(value).map_err(Into::into)
-    }
-
-    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
-        rmp_serde::from_slice(bytes).map_err(Into::into)
-    }
-}
 #[derive(Debug, Clone, Serialize)]
 pub struct CFSize {
     pub total_bytes: u64,
@@ -48,6 +87,16 @@ pub trait KVStore: Sized {
     fn open<P: AsRef<Path>>(path: P, opts: &Options) -> Result<Self, KvStoreError>;
     fn open_default<P: AsRef<Path>>(path: P) -> Result<Self, KvStoreError>;
     fn open_cf<P, I, N>(opts: &Options, path: P, cfs: I) -> Result<Self, KvStoreError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>;
+    fn open_cf_with_prefixes<P, I, N>(
+        opts: &Options,
+        path: P,
+        cfs: I,
+        extractors: &[(&str, PrefixExtractor)],
+    ) -> Result<Self, KvStoreError>
     where
         P: AsRef<Path>,
         I: IntoIterator<Item = N>,
@@ -56,7 +105,16 @@ pub trait KVStore: Sized {
         opts: &Options,
         path: P,
     ) -> Result<Self, KvStoreError>;
+    fn open_read_only<P: AsRef<Path>>(opts: &Options, path: P) -> Result<Self, KvStoreError>;
     fn cf_handle(&self, cf: &str) -> Result<Arc<rocksdb::BoundColumnFamily>, KvStoreError>;
+    fn with_serializer(self, format: Format) -> Self;
+    fn set_cf_serializer(&self, cf: &str, format: Format);
+    fn cf_serializer(&self, cf: &str) -> Format;
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    fn set_cf_compression(&self, cf: &str, compression: Compression, threshold: usize);
+    fn register_schema(&self, cf: &str, schema: Schema);
+    fn migrate_cf(&self, cf: &str) -> Result<usize, KvStoreError>;
+    fn rewrite_cf<T: DeserializeOwned + Serialize>(&self, cf: &str) -> Result<usize, KvStoreError>;
     fn save(&self, k: &str, v: &[u8]) -> Result<(), KvStoreError>;
     fn find(&self, k: &str) -> Result<Option<Vec<u8>>, KvStoreError>;
     fn delete(&self, k: &str) -> Result<(), KvStoreError>;
@@ -69,15 +127,36 @@ pub trait KVStore: Sized {
         cf: &str,
         items: &[(&str, &T)],
     ) -> Result<(), KvStoreError>;
+    fn batch(&self) -> Batch<'_>;
     fn create_cf(&self, name: &str) -> Result<(), KvStoreError>;
+    fn create_cf_with_prefix(
+        &self,
+        name: &str,
+        extractor: PrefixExtractor,
+    ) -> Result<(), KvStoreError>;
     fn cf_exists(&self, name: &str) -> bool;
     fn insert_cf<T: Serialize>(&self, cf: &str, key: &str, value: &T) -> Result<(), KvStoreError>;
     fn get_cf<T: DeserializeOwned>(&self, cf: &str, key: &str) -> Result<T, KvStoreError>;
     fn delete_cf(&self, cf: &str, key: &str) -> Result<(), KvStoreError>;
     fn drop_cf(&self, cf: &str) -> Result<(), KvStoreError>;
     fn get_cf_size(&self, cf: &str) -> Result<CFSize, KvStoreError>;
-    fn create_backup(&self, cf: &str, path: &str) -> Result<(), KvStoreError>;
+    fn flush_cf(&self, cf: &str) -> Result<(), KvStoreError>;
+    fn split_cf(&self, cf: &str, parts: usize) -> Result<Vec<KeyRange>, KvStoreError>;
+    fn create_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), KvStoreError>;
+    fn create_backup(&self, cf: &str, path: &str) -> Result<BackupManifest, KvStoreError>;
     fn restore_backup(&self, cf: &str, path: &str) -> Result<(), KvStoreError>;
+    fn restore_backup_with(
+        &self,
+        cf: &str,
+        path: &str,
+        options: &RestoreOptions,
+    ) -> Result<(), KvStoreError>;
+    fn export_cfs<P: AsRef<Path>>(
+        &self,
+        cfs: &[&str],
+        dir: P,
+    ) -> Result<ExportManifest, KvStoreError>;
+    fn import_cfs<P: AsRef<Path>>(&self, dir: P) -> Result<ExportManifest, KvStoreError>;
     fn query_cf<T: DeserializeOwned + Serialize>(
         &self,
         cf: &str,
@@ -88,39 +167,638 @@ pub trait KVStore: Sized {
         cf: &str,
         query: &str,
     ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>;
-    fn get_range_cf<T: DeserializeOwned + Serialize>(
+    fn query_cf_with_options<T: DeserializeOwned + Serialize, U: DeserializeOwned>(
+        &self,
+        cf: &str,
+        query: &str,
+        options: &QueryOptions,
+    ) -> Result<Vec<KeyValuePair<U>>, KvStoreError>;
+    fn query_cf_by_keys<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        keys: &KeyFilter,
+        query: &str,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>;
+    fn query_cf_by_keys_with_context<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        keys: &KeyFilter,
+        query: &str,
+        context: &QueryContext,
+    ) -> Result<QueryOutcome<T>, KvStoreError>;
+    fn par_query_cf<T: DeserializeOwned + Serialize + Send>(
+        &self,
+        cf: &str,
+        query: &str,
+        threads: usize,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>;
+    fn par_query_cf_with_context<T: DeserializeOwned + Serialize + Send>(
+        &self,
+        cf: &str,
+        query: &str,
+        threads: usize,
+        context: &QueryContext,
+    ) -> Result<QueryOutcome<T>, KvStoreError>;
+    fn query_cf_with_context<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        query: &str,
+        context: &QueryContext,
+    ) -> Result<QueryOutcome<T>, KvStoreError>;
+    fn filter_cf<T, F>(&self, cf: &str, predicate: F) -> Result<Vec<KeyValuePair<T>>, KvStoreError>
+    where
+        T: DeserializeOwned,
+        F: FnMut(&str, &T) -> bool;
+    fn filter_cf_where<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        predicate: &Predicate,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>;
+    fn get_range_cf<T: DeserializeOwned, R: Into<KeyRange>>(
         &self,
         cf: &str,
-        from: &str,
-        to: &str,
+        range: R,
         limit: usize,
         direction: Direction,
     ) -> Result<Vec<T>, KvStoreError>;
-    fn get_range_cf_with_keys<T: DeserializeOwned + Serialize>(
+    fn get_range_cf_with_keys<T: DeserializeOwned, R: Into<KeyRange>>(
         &self,
         cf: &str,
-        from: &str,
-        to: &str,
+        range: R,
         limit: usize,
         direction: Direction,
     ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>;
+    fn page_cf<T: DeserializeOwned, R: Into<KeyRange>>(
+        &self,
+        cf: &str,
+        range: R,
+        page_size: usize,
+        direction: Direction,
+        token: Option<&PageToken>,
+    ) -> Result<Page<T>, KvStoreError>;
+    fn page_cf_with_keys<T: DeserializeOwned, R: Into<KeyRange>>(
+        &self,
+        cf: &str,
+        range: R,
+        page_size: usize,
+        direction: Direction,
+        token: Option<&PageToken>,
+    ) -> Result<Page<KeyValuePair<T>>, KvStoreError>;
+    fn scan_prefix_cf<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        prefix: &str,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>;
+    fn iter_cf<T: DeserializeOwned, R: Into<KeyRange>>(
+        &self,
+        cf: &str,
+        range: R,
+        direction: Direction,
+    ) -> Result<TypedIter<'_, T>, KvStoreError>;
+    fn create_index<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        name: &str,
+        path: &str,
+    ) -> Result<(), KvStoreError>;
+    fn drop_index(&self, cf: &str, name: &str) -> Result<(), KvStoreError>;
+    fn find_by_index_cf<T: DeserializeOwned, V: Serialize>(
+        &self,
+        cf: &str,
+        index: &str,
+        value: &V,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>;
+    fn find_by_index_range_cf<T: DeserializeOwned, V: Serialize, R: RangeBounds<V>>(
+        &self,
+        cf: &str,
+        index: &str,
+        range: R,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>;
+    fn explain_query(&self, cf: &str, query: &str) -> Result<QueryPlan, KvStoreError>;
+    fn aggregate_cf<R: DeserializeOwned>(
+        &self,
+        cf: &str,
+        aggregation: &Aggregation,
+    ) -> Result<BTreeMap<String, R>, KvStoreError>;
 }
 
+const WRITE_BATCH_SIZE: usize = 1000;
+const FETCH_BATCH_SIZE: usize = 1000;
+
 #[derive(Clone)]
 pub struct RocksDB {
     db: Arc<DB>,
+    serializers: SerializerRegistry,
+    prefix_extractors: Arc<RwLock<HashMap<String, PrefixExtractor>>>,
+    indexes: IndexRegistry,
+}
+
+impl RocksDB {
+    fn new(db: DB) -> Self {
+        RocksDB {
+            db: Arc::new(db),
+            serializers: SerializerRegistry::default(),
+            prefix_extractors: Arc::default(),
+            indexes: IndexRegistry::default(),
+        }
+    }
+
+    /// Registers `extractors` after checking them against the ones recorded
+    /// for the open column families, and records those not recorded yet.
+    fn with_prefix_extractors(
+        self,
+        extractors: &[(&str, PrefixExtractor)],
+    ) -> Result<Self, KvStoreError> {
+        if let Some(prefix_cf) = self.db.cf_handle(PREFIX_CF) {
+            for item in self.db.iterator_cf(&prefix_cf, IteratorMode::Start) {
+                let (cf, recorded) = item?;
+                let cf = String::from_utf8(cf.to_vec())?;
+                let recorded = String::from_utf8(recorded.to_vec())?;
+                if !self.cf_exists(&cf) {
+                    continue;
+                }
+                match extractors.iter().find(|(name, _)| *name == cf) {
+                    Some((_, extractor)) if extractor.name() == recorded => {}
+                    Some((_, extractor)) => {
+                        return Err(KvStoreError::InvalidColumnFamily(format!(
+                            "{cf} was created with prefix extractor {recorded}, not {}",
+                            extractor.name()
+                        )));
+                    }
+                    None => {
+                        return Err(KvStoreError::InvalidColumnFamily(format!(
+                            "{cf} was created with prefix extractor {recorded}; \
+                             open it with open_cf_with_prefixes"
+                        )));
+                    }
+                }
+            }
+        }
+
+        for (cf, extractor) in extractors {
+            self.record_prefix_extractor(cf, extractor)?;
+        }
+        Ok(self)
+    }
+
+    fn record_prefix_extractor(
+        &self,
+        cf: &str,
+        extractor: &PrefixExtractor,
+    ) -> Result<(), KvStoreError> {
+        if !self.cf_exists(PREFIX_CF) {
+            self.db.create_cf(PREFIX_CF, &Options::default())?;
+        }
+        let prefix_cf = self.cf_handle(PREFIX_CF)?;
+        self.db.put_cf(&prefix_cf, cf, extractor.name())?;
+        self.prefix_extractors
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(cf.to_string(), *extractor);
+        Ok(())
+    }
+
+    /// Registers the indexes whose definitions are stored in the index column
+    /// families among `cf_names`. An index without a definition was never
+    /// finished building and stays unregistered until `create_index` rebuilds
+    /// it.
+    fn with_stored_indexes(self, cf_names: &[String]) -> Result<Self, KvStoreError> {
+        for cf_name in cf_names {
+            if !cf_name.starts_with(index::INDEX_CF_PREFIX) {
+                continue;
+            }
+            let index_cf = self.cf_handle(cf_name)?;
+            if let Some(definition) = self.db.get_cf(&index_cf, index::DEFINITION_KEY)? {
+                let index = Index::from_definition(&definition)?;
+                let cf = index.cf().to_owned();
+                self.indexes.add(&cf, index);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Writes every entry `cf` holds in `snapshot` to an SST file at `path`
+    /// and describes it. No file is created for an empty column family, as
+    /// `SstFileWriter` cannot finish one.
+    fn write_sst(
+        &self,
+        cf: &str,
+        snapshot: &Snapshot,
+        path: &Path,
+    ) -> Result<BackupManifest, KvStoreError> {
+        let cf_handle = self.cf_handle(cf)?;
+        let mut manifest = BackupManifest {
+            cf: cf.to_string(),
+            entries: 0,
+            first_key: None,
+            last_key: None,
+            size: 0,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            format: self.serializers.codec(cf).format().id(),
+            checksum: 0,
+        };
+
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_snapshot(snapshot);
+        let mut iter = self
+            .db
+            .iterator_cf_opt(&cf_handle, read_opts, IteratorMode::Start)
+            .peekable();
+        if iter.peek().is_none() {
+            return Ok(manifest);
+        }
+
+        let opts = Options::default();
+        let mut writer = SstFileWriter::create(&opts);
+        writer.open(path)?;
+        let mut last_key = Box::default();
+        for item in iter {
+            let (key, value) = item?;
+            writer.put(&key, &value)?;
+            if manifest.entries == 0 {
+                manifest.first_key = Some(hex::encode(&key));
+            }
+            manifest.entries += 1;
+            last_key = key;
+        }
+        writer.finish()?;
+
+        manifest.last_key = Some(hex::encode(last_key));
+        manifest.size = std::fs::metadata(path)?.len();
+        manifest.checksum = backup::checksum_file(path)?;
+        Ok(manifest)
+    }
+
+    /// Fails unless `cf` uses the format the backup was written with, as
+    /// its values would not decode otherwise.
+    fn check_backup_format(&self, cf: &str, manifest: &BackupManifest) -> Result<(), KvStoreError> {
+        let format = self.serializers.codec(cf).format();
+        if manifest.format != format.id() {
+            return Err(KvStoreError::InvalidBackup(format!(
+                "backup of {} was written with format id {}, but {cf} uses {format:?}",
+                manifest.cf, manifest.format
+            )));
+        }
+        Ok(())
+    }
+
+    /// Ingested files bypass index maintenance, so indexed column families
+    /// are refused rather than left with stale indexes.
+    fn check_not_indexed(&self, cf: &str) -> Result<(), KvStoreError> {
+        if self.indexes.is_indexed(cf) {
+            return Err(KvStoreError::InvalidColumnFamily(format!(
+                "{cf} has indexes; drop them before restoring into it"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Ingests a copy of the file at `path` into a scratch column family to
+    /// check what it holds against `manifest`, as the file can't be read
+    /// directly. The scratch column family is dropped again either way.
+    fn check_sst_contents(
+        &self,
+        manifest: &BackupManifest,
+        path: &Path,
+    ) -> Result<(), KvStoreError> {
+        if manifest.entries == 0 {
+            return Ok(());
+        }
+        if self.cf_exists(backup::CHECK_CF) {
+            self.db.drop_cf(backup::CHECK_CF)?;
+        }
+        self.db.create_cf(backup::CHECK_CF, &Options::default())?;
+        let checked = self
+            .ingest_sst(backup::CHECK_CF, path, &RestoreOptions::default())
+            .and_then(|()| {
+                let live_files = self.db.live_files()?;
+                let file = live_files
+                    .iter()
+                    .find(|file| file.column_family_name == backup::CHECK_CF)
+                    .ok_or_else(|| {
+                        KvStoreError::InvalidBackup(format!("{} was not ingested", path.display()))
+                    })?;
+                manifest.check_contents(file)
+            });
+        self.db.drop_cf(backup::CHECK_CF)?;
+        checked
+    }
+
+    fn ingest_sst(
+        &self,
+        cf: &str,
+        path: &Path,
+        options: &RestoreOptions,
+    ) -> Result<(), KvStoreError> {
+        let cf_handle = self.cf_handle(cf)?;
+        let ingest_opts = options.ingest_options();
+        self.db
+            .ingest_external_file_cf_opts(&cf_handle, &ingest_opts, vec![path])?;
+        Ok(())
+    }
+
+    /// Writes `batch` together with the index updates for `changes`.
+    pub(crate) fn write_indexed(
+        &self,
+        mut batch: WriteBatch,
+        changes: Vec<IndexedChange>,
+    ) -> Result<(), KvStoreError> {
+        if changes.is_empty() {
+            return self.db.write(batch).map_err(KvStoreError::from);
+        }
+
+        let _guard = self.indexes.lock();
+        let mut staged = HashMap::new();
+        for change in &changes {
+            for index in self.indexes.for_cf(&change.cf) {
+                self.stage_index_update(
+                    &mut batch,
+                    &index,
+                    &change.key,
+                    change.doc.as_ref(),
+                    &mut staged,
+                )?;
+            }
+        }
+        self.db.write(batch).map_err(KvStoreError::from)
+    }
+
+    /// Replaces the entries `index` holds for `key` with those of `doc`.
+    /// `staged` tracks entries already replaced earlier in the same batch,
+    /// which the stored reverse entry does not reflect yet.
+    fn stage_index_update(
+        &self,
+        batch: &mut WriteBatch,
+        index: &Index,
+        key: &str,
+        doc: Option<&serde_json::Value>,
+        staged: &mut HashMap<(String, String), Vec<Vec<u8>>>,
+    ) -> Result<(), KvStoreError> {
+        let index_cf = self.cf_handle(index.cf_name())?;
+        let primary_key = key.as_bytes();
+        let reverse_key = index::reverse_key(primary_key);
+
+        let staged_key = (index.cf_name().to_string(), key.to_string());
+        let old = match staged.remove(&staged_key) {
+            Some(values) => values,
+            None => self
+                .db
+                .get_cf(&index_cf, &reverse_key)?
+                .map(|bytes| index::split_values(&bytes))
+                .unwrap_or_default(),
+        };
+        let new = doc.map(|doc| index.values(doc)).unwrap_or_default();
+
+        for value in old.iter().filter(|value| !new.contains(value)) {
+            batch.delete_cf(&index_cf, index::entry_key(value, primary_key));
+        }
+        for value in &new {
+            batch.put_cf(&index_cf, index::entry_key(value, primary_key), []);
+        }
+        if new.is_empty() {
+            batch.delete_cf(&index_cf, &reverse_key);
+        } else {
+            batch.put_cf(&index_cf, &reverse_key, new.concat());
+        }
+
+        staged.insert(staged_key, new);
+        Ok(())
+    }
+
+    /// Primary keys whose value in `index` falls in `range`, in index order.
+    pub(crate) fn index_lookup<V: Serialize, R: RangeBounds<V>>(
+        &self,
+        cf: &str,
+        index: &str,
+        range: &R,
+    ) -> Result<Vec<Vec<u8>>, KvStoreError> {
+        let index = self.indexes.get(cf, index).ok_or_else(|| {
+            KvStoreError::InvalidQuery(format!("no index named {} on {}", index, cf))
+        })?;
+        if !index.is_built() {
+            return Err(KvStoreError::InvalidQuery(format!(
+                "index {} on {} is still being built",
+                index.name(),
+                cf
+            )));
+        }
+        let index_cf = self.cf_handle(index.cf_name())?;
+        let range = index::entry_range(range)?;
+
+        let mut seen = HashSet::new();
+        let mut keys = Vec::new();
+        let iter = self.db.iterator_cf_opt(
+            &index_cf,
+            range.read_options(),
+            range.iterator_mode(Direction::Forward),
+        );
+        for item in iter {
+            let (entry, _) = item?;
+            if !range.contains(&entry) {
+                continue;
+            }
+            // Array values put one document under several entries
+            if let Some(primary_key) = index::primary_key(&entry) {
+                if seen.insert(primary_key.to_vec()) {
+                    keys.push(primary_key.to_vec());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Calls `visit` with every document matching `planned` whose key passes
+    /// `keys`, together with its JSON form, until it returns false. Index
+    /// candidates are loaded in key order, the same order a full scan yields
+    /// them in. `context` is checked before each document is evaluated.
+    fn for_each_match<T, F>(
+        &self,
+        cf: &str,
+        keys: Option<&KeyFilter>,
+        planned: &PlannedQuery,
+        context: &QueryContext,
+        mut visit: F,
+    ) -> Result<(), KvStoreError>
+    where
+        T: DeserializeOwned + Serialize,
+        F: FnMut(KeyValuePair<T>, serde_json::Value) -> bool,
+    {
+        let mut scanned = 0;
+        let mut check = || {
+            scanned += 1;
+            context.check(scanned)
+        };
+
+        if let Some(lookup) = &planned.lookup {
+            let range = (lookup.start.clone(), lookup.end.clone());
+            let mut candidates = self.index_lookup(cf, &lookup.index, &range)?;
+            if let Some(keys) = keys {
+                candidates.retain(|key| keys.matches(key));
+            }
+            candidates.sort();
+
+            // Candidates are loaded a chunk at a time, so a deadline or
+            // cancellation stops the scan before everything was fetched
+            for chunk in candidates.chunks(FETCH_BATCH_SIZE) {
+                context.check(0)?;
+                for pair in self.fetch_pairs::<T>(cf, chunk.to_vec())? {
+                    check()?;
+                    if let Some(doc) = planned.select(to_json(&pair.value)?) {
+                        if !visit(pair, doc) {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        // Filters are checked one document at a time, so only matches are kept.
+        // Values are only decoded into `T` once the fields the filter reads
+        // have matched.
+        if planned.is_per_document() {
+            let mut iter = self.scan_keys::<T>(cf, keys)?;
+            while let Some(item) = iter.next_raw() {
+                check()?;
+                let (key, value) = item?;
+                if planned.rejects_raw(iter.serializer(), &value) {
+                    continue;
+                }
+                let pair = iter.decode(&key, &value)?;
+                if let Some(doc) = planned.select(to_json(&pair.value)?) {
+                    if !visit(pair, doc) {
+                        break;
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        // Anything else sees the whole column family as one array; matches are
+        // mapped back to documents by position
+        let mut pairs = Vec::new();
+        let mut docs = Vec::new();
+        for item in self.scan_keys::<T>(cf, keys)? {
+            check()?;
+            let pair = item?;
+            docs.push(to_json(&pair.value)?);
+            pairs.push(Some(pair));
+        }
+        let mut docs = serde_json::Value::Array(docs);
+        for i in planned.positions(&docs)? {
+            if let Some(pair) = pairs.get_mut(i).and_then(Option::take) {
+                if !visit(pair, docs[i].take()) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterates the documents whose key passes `keys`, or the whole column
+    /// family without a filter.
+    fn scan_keys<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        keys: Option<&KeyFilter>,
+    ) -> Result<TypedIter<'_, T>, KvStoreError> {
+        match keys {
+            Some(keys) => {
+                let range = keys.scan_range();
+                let opts = range.read_options();
+                Ok(self
+                    .typed_iter(cf, range, opts, Direction::Forward)?
+                    .filter_keys(keys.clone()))
+            }
+            None => self.iter_cf(cf, .., Direction::Forward),
+        }
+    }
+
+    /// Loads the documents stored under `keys`, skipping missing ones.
+    pub(crate) fn fetch_pairs<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        keys: Vec<Vec<u8>>,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
+        let cf_handle = self.cf_handle(cf)?;
+        let serializer = self.serializers.for_cf(cf);
+
+        let values = self
+            .db
+            .multi_get_cf(keys.iter().map(|key| (&cf_handle, key)));
+
+        let mut results = Vec::with_capacity(keys.len());
+        for (key, value) in keys.into_iter().zip(values) {
+            if let Some(value) = value? {
+                results.push(KeyValuePair {
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    value: serializer.deserialize(&value)?,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    /// Scans `range` in `direction`, returning raw keys so callers can build
+    /// continuation tokens from them.
+    fn scan_range<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        range: &KeyRange,
+        limit: usize,
+        direction: Direction,
+    ) -> Result<Vec<(Vec<u8>, T)>, KvStoreError> {
+        self.scan_range_opt(cf, range, range.read_options(), limit, direction)
+    }
+
+    fn scan_range_opt<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        range: &KeyRange,
+        opts: ReadOptions,
+        limit: usize,
+        direction: Direction,
+    ) -> Result<Vec<(Vec<u8>, T)>, KvStoreError> {
+        let mut iter = self.typed_iter(cf, range.clone(), opts, direction)?;
+        let mut results = Vec::new();
+        while results.len() < limit {
+            match iter.next_entry() {
+                Some(entry) => results.push(entry?),
+                None => break,
+            }
+        }
+        Ok(results)
+    }
+
+    fn typed_iter<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        range: KeyRange,
+        opts: ReadOptions,
+        direction: Direction,
+    ) -> Result<TypedIter<'_, T>, KvStoreError> {
+        let cf_handle = self.cf_handle(cf)?;
+        Ok(TypedIter::new(
+            &self.db,
+            &cf_handle,
+            range,
+            opts,
+            direction,
+            self.serializers.for_cf(cf),
+        ))
+    }
 }
 
 impl KVStore for RocksDB {
     fn open<P: AsRef<Path>>(path: P, opts: &Options) -> Result<Self, KvStoreError> {
         DB::open(opts, path)
-            .map(|db| RocksDB { db: Arc::new(db) })
+            .map(RocksDB::new)
             .map_err(KvStoreError::from)
     }
 
     fn open_default<P: AsRef<Path>>(path: P) -> Result<Self, KvStoreError> {
         DB::open_default(path)
-            .map(|db| RocksDB { db: Arc::new(db) })
+            .map(RocksDB::new)
             .map_err(KvStoreError::from)
     }
 
@@ -130,9 +808,48 @@ impl KVStore for RocksDB {
         I: IntoIterator<Item = N>,
         N: AsRef<str>,
     {
-        let cf_names: Vec<String> = cfs.into_iter().map(|n| n.as_ref().to_string()).collect();
-        let db = DB::open_cf(opts, path, cf_names)?;
-        Ok(RocksDB { db: Arc::new(db) })
+        Self::open_cf_with_prefixes(opts, path, cfs, &[])
+    }
+
+    // Column families with an extractor are opened with its options, and
+    // added to `cfs` if missing. Every column family created with an extractor
+    // must be given the same one again; the record of extractors is opened
+    // whenever it exists so this can be checked.
+    fn open_cf_with_prefixes<P, I, N>(
+        opts: &Options,
+        path: P,
+        cfs: I,
+        extractors: &[(&str, PrefixExtractor)],
+    ) -> Result<Self, KvStoreError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>,
+    {
+        let mut cf_names: Vec<String> = cfs.into_iter().map(|n| n.as_ref().to_string()).collect();
+        for (cf, _) in extractors {
+            if !cf_names.iter().any(|name| name == cf) {
+                cf_names.push(cf.to_string());
+            }
+        }
+        let existing = DB::list_cf(opts, &path).unwrap_or_default();
+        if existing.iter().any(|name| name == PREFIX_CF)
+            && !cf_names.iter().any(|name| name == PREFIX_CF)
+        {
+            cf_names.push(PREFIX_CF.to_string());
+        }
+
+        let descriptors = cf_names.iter().map(|name| {
+            let cf_opts = match extractors.iter().find(|(cf, _)| cf == name) {
+                Some((_, extractor)) => extractor.options(),
+                None => opts.clone(),
+            };
+            ColumnFamilyDescriptor::new(name, cf_opts)
+        });
+        let db = DB::open_cf_descriptors(opts, path, descriptors)?;
+        RocksDB::new(db)
+            .with_prefix_extractors(extractors)?
+            .with_stored_indexes(&cf_names)
     }
     fn open_with_existing_cfs<P: AsRef<Path>>(
         opts: &Options,
@@ -154,11 +871,112 @@ impl KVStore for RocksDB {
 
         Self::open_cf(opts, path, cf_names)
     }
+    // Every column family is opened; writes fail with a DbError. Prefix
+    // extractors aren't applied, so prefix scans use total-order seeks.
+    fn open_read_only<P: AsRef<Path>>(opts: &Options, path: P) -> Result<Self, KvStoreError> {
+        let cf_names = DB::list_cf(opts, &path)?;
+        let db = DB::open_cf_for_read_only(opts, path, &cf_names, false)?;
+        RocksDB::new(db).with_stored_indexes(&cf_names)
+    }
+
     fn cf_handle(&self, cf: &str) -> Result<Arc<rocksdb::BoundColumnFamily>, KvStoreError> {
         self.db
             .cf_handle(cf)
             .ok_or_else(|| KvStoreError::InvalidColumnFamily(cf.to_string()))
     }
+    fn with_serializer(mut self, format: Format) -> Self {
+        self.serializers = self.serializers.with_format(format);
+        self
+    }
+    fn set_cf_serializer(&self, cf: &str, format: Format) {
+        self.serializers.set_format(cf, format);
+    }
+    fn cf_serializer(&self, cf: &str) -> Format {
+        self.serializers.codec(cf).format()
+    }
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    fn set_cf_compression(&self, cf: &str, compression: Compression, threshold: usize) {
+        self.serializers.set_compression(cf, compression, threshold);
+    }
+    /// From now on values written to `cf` start with the binary
+    /// `[0xC1, 0xEE, format, version]` envelope, JSON ones included; reading
+    /// them without this crate means stripping its 7 bytes first.
+    fn register_schema(&self, cf: &str, schema: Schema) {
+        self.serializers.register_schema(cf, schema);
+    }
+    // Upgraded documents of indexed column families are read back as JSON to
+    // update their index entries, which needs a self-describing format
+    fn migrate_cf(&self, cf: &str) -> Result<usize, KvStoreError> {
+        let cf_handle = self.cf_handle(cf)?;
+        let serializer = self.serializers.for_cf(cf);
+        let _guard = self.indexes.lock();
+        let indexes = self.indexes.for_cf(cf);
+
+        let mut batch = WriteBatch::default();
+        let mut staged = HashMap::new();
+        let mut migrated = 0;
+        for item in self.db.iterator_cf(&cf_handle, IteratorMode::Start) {
+            let (key, value) = item?;
+            if let Some(upgraded) = serializer.upgrade(&value)? {
+                if !indexes.is_empty() {
+                    let doc: serde_json::Value = serializer.deserialize(&upgraded)?;
+                    let key = String::from_utf8(key.to_vec())?;
+                    for index in &indexes {
+                        self.stage_index_update(&mut batch, index, &key, Some(&doc), &mut staged)?;
+                    }
+                }
+                batch.put_cf(&cf_handle, &key, upgraded);
+                migrated += 1;
+            }
+
+            // Flush periodically so huge column families don't build one giant batch
+            if batch.len() >= WRITE_BATCH_SIZE {
+                self.db.write(std::mem::take(&mut batch))?;
+                staged.clear();
+            }
+        }
+        self.db.write(batch)?;
+
+        Ok(migrated)
+    }
+
+    // Decodes every value as `T` and writes it back with the current
+    // serializer, e.g. to turn MessagePack written as positional arrays into
+    // maps keyed by field name. Only values whose bytes change are written.
+    fn rewrite_cf<T: DeserializeOwned + Serialize>(&self, cf: &str) -> Result<usize, KvStoreError> {
+        let cf_handle = self.cf_handle(cf)?;
+        let serializer = self.serializers.for_cf(cf);
+        let _guard = self.indexes.lock();
+        let indexes = self.indexes.for_cf(cf);
+
+        let mut batch = WriteBatch::default();
+        let mut staged = HashMap::new();
+        let mut rewritten = 0;
+        for item in self.db.iterator_cf(&cf_handle, IteratorMode::Start) {
+            let (key, value) = item?;
+            let decoded: T = serializer.deserialize(&value)?;
+            let encoded = serializer.serialize(&decoded)?;
+            if encoded[..] != value[..] {
+                if !indexes.is_empty() {
+                    let doc = to_json(&decoded)?;
+                    let key = String::from_utf8(key.to_vec())?;
+                    for index in &indexes {
+                        self.stage_index_update(&mut batch, index, &key, Some(&doc), &mut staged)?;
+                    }
+                }
+                batch.put_cf(&cf_handle, &key, encoded);
+                rewritten += 1;
+            }
+
+            if batch.len() >= WRITE_BATCH_SIZE {
+                self.db.write(std::mem::take(&mut batch))?;
+                staged.clear();
+            }
+        }
+        self.db.write(batch)?;
+
+        Ok(rewritten)
+    }
     fn list_cf(path: &str) -> Result<Vec<String>, KvStoreError> {
         let cf_names = DB::list_cf(&Options::default(), path).map_err(KvStoreError::from)?;
         Ok(cf_names)
@@ -179,22 +997,16 @@ impl KVStore for RocksDB {
         let value = self
             .find(key)?
             .ok_or_else(|| KvStoreError::KeyNotFound(key.to_string()))?;
-        MessagePackSerializer.deserialize(&value)
+        self.serializers
+            .for_cf(DEFAULT_COLUMN_FAMILY_NAME)
+            .deserialize(&value)
     }
 
     fn insert<T: Serialize>(&self, key: &str, v: &T) -> Result<(), KvStoreError> {
-        let serialized = MessagePackSerializer.serialize(v)?;
-        self.save(key, &serialized)
+        self.insert_cf(DEFAULT_COLUMN_FAMILY_NAME, key, v)
     }
     fn batch_insert<T: Serialize>(&self, items: &[(&str, &T)]) -> Result<(), KvStoreError> {
-        let mut batch = WriteBatch::default();
-
-        for (key, value) in items {
-            let serialized = MessagePackSerializer.serialize(value)?;
-            batch.put(key.as_bytes(), &serialized);
-        }
-
-        self.db.write(batch).map_err(KvStoreError::from)
+        self.batch_insert_cf(DEFAULT_COLUMN_FAMILY_NAME, items)
     }
     fn batch_insert_cf<T: Serialize>(
         &self,
@@ -202,14 +1014,28 @@ impl KVStore for RocksDB {
         items: &[(&str, &T)],
     ) -> Result<(), KvStoreError> {
         let cf_handle = self.cf_handle(cf)?;
+        let serializer = self.serializers.for_cf(cf);
+
+        let indexed = self.indexes.is_indexed(cf);
 
         let mut batch = WriteBatch::default();
+        let mut changes = Vec::new();
         for (key, value) in items {
-            let serialized = MessagePackSerializer.serialize(value)?;
+            let serialized = serializer.serialize(value)?;
             batch.put_cf(&cf_handle, key.as_bytes(), &serialized);
+            if indexed {
+                changes.push(IndexedChange {
+                    cf: cf.to_string(),
+                    key: key.to_string(),
+                    doc: Some(to_json(value)?),
+                });
+            }
         }
 
-        self.db.write(batch).map_err(KvStoreError::from)
+        self.write_indexed(batch, changes)
+    }
+    fn batch(&self) -> Batch<'_> {
+        Batch::new(self)
     }
     fn create_cf(&self, name: &str) -> Result<(), KvStoreError> {
         if !self.cf_exists(name) {
@@ -220,16 +1046,56 @@ impl KVStore for RocksDB {
         Ok(())
     }
 
+    fn create_cf_with_prefix(
+        &self,
+        name: &str,
+        extractor: PrefixExtractor,
+    ) -> Result<(), KvStoreError> {
+        // RocksDB only applies an extractor when the column family is created
+        // or opened, so it can't be changed here
+        if self.cf_exists(name) {
+            let current = self
+                .prefix_extractors
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(name)
+                .map(PrefixExtractor::name);
+            if current == Some(extractor.name()) {
+                return Ok(());
+            }
+            return Err(KvStoreError::InvalidColumnFamily(format!(
+                "{name} already exists; prefix extractors can only be set when \
+                 creating a column family or with open_cf_with_prefixes"
+            )));
+        }
+        self.db
+            .create_cf(name, &extractor.options())
+            .map_err(KvStoreError::from)?;
+        self.record_prefix_extractor(name, &extractor)
+    }
+
     fn cf_exists(&self, name: &str) -> bool {
         self.db.cf_handle(name).is_some()
     }
     fn insert_cf<T: Serialize>(&self, cf: &str, key: &str, value: &T) -> Result<(), KvStoreError> {
         let cf_handle = self.cf_handle(cf)?;
 
-        let serialized = MessagePackSerializer.serialize(value)?;
-        self.db
-            .put_cf(&cf_handle, key.as_bytes(), serialized)
-            .map_err(KvStoreError::from)
+        let serialized = self.serializers.for_cf(cf).serialize(value)?;
+        if !self.indexes.is_indexed(cf) {
+            return self
+                .db
+                .put_cf(&cf_handle, key.as_bytes(), serialized)
+                .map_err(KvStoreError::from);
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&cf_handle, key.as_bytes(), serialized);
+        let change = IndexedChange {
+            cf: cf.to_string(),
+            key: key.to_string(),
+            doc: Some(to_json(value)?),
+        };
+        self.write_indexed(batch, vec![change])
     }
 
     fn get_cf<T: DeserializeOwned>(&self, cf: &str, key: &str) -> Result<T, KvStoreError> {
@@ -238,7 +1104,7 @@ impl KVStore for RocksDB {
             .db
             .get_cf(&cf_handle, key.as_bytes())?
             .ok_or(KvStoreError::KeyNotFound(key.to_string()))?;
-        MessagePackSerializer.deserialize(&value)
+        self.serializers.for_cf(cf).deserialize(&value)
     }
 
     fn delete_cf(&self, cf: &str, key: &str) -> Result<(), KvStoreError> {
@@ -248,12 +1114,36 @@ impl KVStore for RocksDB {
             .db
             .get_cf(&cf_handle, key.as_bytes())?
             .ok_or(KvStoreError::KeyNotFound(key.to_string()))?;
-        self.db
-            .delete_cf(&cf_handle, key.as_bytes())
-            .map_err(KvStoreError::from)
+        if !self.indexes.is_indexed(cf) {
+            return self
+                .db
+                .delete_cf(&cf_handle, key.as_bytes())
+                .map_err(KvStoreError::from);
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(&cf_handle, key.as_bytes());
+        let change = IndexedChange {
+            cf: cf.to_string(),
+            key: key.to_string(),
+            doc: None,
+        };
+        self.write_indexed(batch, vec![change])
     }
 
     fn drop_cf(&self, cf: &str) -> Result<(), KvStoreError> {
+        for index in self.indexes.remove_cf(cf) {
+            self.db.drop_cf(index.cf_name())?;
+        }
+        let extractor = self
+            .prefix_extractors
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(cf);
+        if extractor.is_some() {
+            let prefix_cf = self.cf_handle(PREFIX_CF)?;
+            self.db.delete_cf(&prefix_cf, cf)?;
+        }
         self.db.drop_cf(cf).map_err(KvStoreError::from)
     }
     fn get_cf_size(&self, cf: &str) -> Result<CFSize, KvStoreError> {
@@ -285,243 +1175,746 @@ impl KVStore for RocksDB {
         })
     }
 
-    fn create_backup(&self, cf: &str, path: &str) -> Result<(), KvStoreError> {
+    /// Writes the column family's memtable out to an SST file, e.g. so that
+    /// `split_cf` accounts for recent writes.
+    fn flush_cf(&self, cf: &str) -> Result<(), KvStoreError> {
         let cf_handle = self.cf_handle(cf)?;
+        self.db.flush_cf(&cf_handle).map_err(KvStoreError::from)
+    }
 
-        // Create a new SST file writer
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        let mut writer = SstFileWriter::create(&opts);
-
-        // Open the writer with the specified file path
-        writer.open(path)?;
-
-        // Get an iterator over the CF
-        let iter = self.db.iterator_cf(&cf_handle, IteratorMode::Start);
-
-        // Write all KV pairs to the SST file
-        for item in iter {
-            let (key, value) = item?;
-            writer.put(&key, &value)?;
+    /// Splits the column family into up to `parts` consecutive key ranges of
+    /// similar size, cutting at SST file boundaries. Data still in the
+    /// memtable is not accounted for, so a CF that was never flushed yields a
+    /// single range. The ranges cover every key and can be scanned
+    /// independently, e.g. one per thread.
+    fn split_cf(&self, cf: &str, parts: usize) -> Result<Vec<KeyRange>, KvStoreError> {
+        if parts == 0 {
+            return Err(KvStoreError::InvalidQuery(
+                "cannot split into zero parts".to_string(),
+            ));
+        }
+        let mut files: Vec<(Vec<u8>, usize)> = self
+            .db
+            .live_files()?
+            .into_iter()
+            .filter(|file| file.column_family_name == cf)
+            .filter_map(|file| Some((file.start_key?, file.size)))
+            .collect();
+        files.sort();
+
+        let total: usize = files.iter().map(|(_, size)| size).sum();
+        let mut splits: Vec<Vec<u8>> = Vec::new();
+        let mut seen = 0;
+        for (start, size) in files {
+            let target = total / parts * (splits.len() + 1);
+            if seen > 0
+                && seen >= target
+                && splits.len() + 1 < parts
+                && splits.last() != Some(&start)
+            {
+                splits.push(start);
+            }
+            seen += size;
         }
 
-        // Finish writing and close the file
-        writer.finish()?;
+        let mut ranges = Vec::with_capacity(splits.len() + 1);
+        let mut start = Bound::Unbounded;
+        for split in splits {
+            ranges.push(KeyRange {
+                start,
+                end: Bound::Excluded(split.clone()),
+            });
+            start = Bound::Included(split);
+        }
+        ranges.push(KeyRange {
+            start,
+            end: Bound::Unbounded,
+        });
+        Ok(ranges)
+    }
 
+    // SST files are hard-linked when `path` is on the same filesystem, so this
+    // is fast regardless of the database size. `path` must not exist yet.
+    fn create_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), KvStoreError> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
         Ok(())
     }
 
+    // Reads from a snapshot, so writes made while the file is written are not
+    // included. The manifest is written next to the file, which is skipped
+    // for an empty column family.
+    fn create_backup(&self, cf: &str, path: &str) -> Result<BackupManifest, KvStoreError> {
+        let snapshot = self.db.snapshot();
+        let manifest = self.write_sst(cf, &snapshot, Path::new(path))?;
+        manifest.write(&BackupManifest::path_for(path))?;
+        Ok(manifest)
+    }
+
     fn restore_backup(&self, cf: &str, path: &str) -> Result<(), KvStoreError> {
-        let cf_handle = self.cf_handle(cf)?;
+        self.restore_backup_with(cf, path, &RestoreOptions::default())
+    }
+
+    // Refuses files whose manifest is missing, names another column family
+    // (unless restoring into a new one), or does not match the file's size,
+    // checksum, entry count or key range
+    fn restore_backup_with(
+        &self,
+        cf: &str,
+        path: &str,
+        options: &RestoreOptions,
+    ) -> Result<(), KvStoreError> {
+        let manifest = BackupManifest::read(BackupManifest::path_for(path))?;
+        self.check_not_indexed(cf)?;
+        match options.mode {
+            RestoreMode::IntoNew if self.cf_exists(cf) => {
+                return Err(KvStoreError::InvalidColumnFamily(format!(
+                    "{cf} already exists"
+                )));
+            }
+            RestoreMode::IntoNew => {}
+            RestoreMode::Merge | RestoreMode::Replace if manifest.cf != cf => {
+                return Err(KvStoreError::InvalidBackup(format!(
+                    "{path} is a backup of {}, not {cf}",
+                    manifest.cf
+                )));
+            }
+            RestoreMode::Merge => {
+                self.cf_handle(cf)?;
+            }
+            RestoreMode::Replace if cf == DEFAULT_COLUMN_FAMILY_NAME => {
+                return Err(KvStoreError::InvalidColumnFamily(
+                    "the default column family can't be dropped, so it can't be replaced"
+                        .to_string(),
+                ));
+            }
+            RestoreMode::Replace => {}
+        }
+        self.check_backup_format(cf, &manifest)?;
+        manifest.verify(path)?;
+        self.check_sst_contents(&manifest, Path::new(path))?;
+
+        match options.mode {
+            RestoreMode::Merge => {}
+            // Recreated with its registered prefix extractor, if it has one
+            RestoreMode::Replace => {
+                let extractor = self
+                    .prefix_extractors
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(cf)
+                    .copied();
+                if self.cf_exists(cf) {
+                    self.drop_cf(cf)?;
+                }
+                match extractor {
+                    Some(extractor) => self.create_cf_with_prefix(cf, extractor)?,
+                    None => self.create_cf(cf)?,
+                }
+            }
+            RestoreMode::IntoNew => self.create_cf(cf)?,
+        }
+        if manifest.entries == 0 {
+            return Ok(());
+        }
+        self.ingest_sst(cf, Path::new(path), options)
+    }
 
-        // Create ingest options
-        let ingest_opts = IngestExternalFileOptions::default();
+    // Writes one SST file per column family, all from the same snapshot, and
+    // the manifest last so a partial export is never mistaken for a whole one
+    fn export_cfs<P: AsRef<Path>>(
+        &self,
+        cfs: &[&str],
+        dir: P,
+    ) -> Result<ExportManifest, KvStoreError> {
+        let dir = dir.as_ref();
+        if dir.join(backup::EXPORT_MANIFEST).exists() {
+            return Err(KvStoreError::IoError(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already holds an export", dir.display()),
+            )));
+        }
+        std::fs::create_dir_all(dir)?;
+
+        let snapshot = self.db.snapshot();
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut column_families = Vec::with_capacity(cfs.len());
+        for (i, cf) in cfs.iter().enumerate() {
+            let file = format!("cf-{i}.sst");
+            let manifest = self.write_sst(cf, &snapshot, &dir.join(&file))?;
+            column_families.push(CfExport {
+                file: (manifest.entries > 0).then_some(file),
+                manifest,
+            });
+        }
 
-        // Ingest the SST file
-        self.db
-            .ingest_external_file_cf_opts(&cf_handle, &ingest_opts, vec![path])?;
+        let manifest = ExportManifest {
+            created_at,
+            column_families,
+        };
+        manifest.write(dir)?;
+        Ok(manifest)
+    }
 
-        Ok(())
+    // Column families missing from the database are created first. Every file
+    // is verified, and indexed column families refused, before any of them is
+    // ingested.
+    fn import_cfs<P: AsRef<Path>>(&self, dir: P) -> Result<ExportManifest, KvStoreError> {
+        let dir = dir.as_ref();
+        let manifest = ExportManifest::read(dir)?;
+        for export in &manifest.column_families {
+            self.check_not_indexed(&export.manifest.cf)?;
+            self.check_backup_format(&export.manifest.cf, &export.manifest)?;
+            if let Some(file) = &export.file {
+                export.manifest.verify(dir.join(file))?;
+                self.check_sst_contents(&export.manifest, &dir.join(file))?;
+            }
+        }
+        for export in &manifest.column_families {
+            self.create_cf(&export.manifest.cf)?;
+            if let Some(file) = &export.file {
+                self.ingest_sst(
+                    &export.manifest.cf,
+                    &dir.join(file),
+                    &RestoreOptions::default(),
+                )?;
+            }
+        }
+        Ok(manifest)
     }
     fn query_cf<T: DeserializeOwned + Serialize>(
         &self,
         cf: &str,
         query: &str,
     ) -> Result<Vec<T>, KvStoreError> {
-        let cf_handle = self
-            .db
-            .cf_handle(cf)
-            .ok_or_else(|| KvStoreError::InvalidColumnFamily(cf.to_string()))?;
+        Ok(self
+            .query_cf_with_keys(cf, query)?
+            .into_iter()
+            .map(|pair| pair.value)
+            .collect())
+    }
 
-        // Collect all document data
-        let mut documents = Vec::new();
-        let mut json_values = Vec::new();
+    // Query implementation with key-value pairs
+    fn query_cf_with_keys<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        query: &str,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
+        let outcome = self.query_cf_with_context(cf, query, &QueryContext::default())?;
+        Ok(outcome.items)
+    }
 
-        // Single pass collection of data
-        for item in self.db.iterator_cf(&cf_handle, IteratorMode::Start) {
-            let (_, value_bytes) = item?;
-            let value: T = MessagePackSerializer.deserialize(&value_bytes)?;
+    fn query_cf_by_keys<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        keys: &KeyFilter,
+        query: &str,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
+        let outcome =
+            self.query_cf_by_keys_with_context(cf, keys, query, &QueryContext::default())?;
+        Ok(outcome.items)
+    }
 
-            // Convert to JSON for querying only once
-            let json_value = serde_json::to_value(&value)
-                .map_err(|e| KvStoreError::SerializationError(e.to_string()))?;
+    fn query_cf_by_keys_with_context<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        keys: &KeyFilter,
+        query: &str,
+        context: &QueryContext,
+    ) -> Result<QueryOutcome<T>, KvStoreError> {
+        let planned = query::plan(query, &self.indexes.for_cf(cf))?;
+        let mut items = Vec::new();
+        let scan = self.for_each_match::<T, _>(cf, Some(keys), &planned, context, |pair, _| {
+            items.push(pair);
+            true
+        });
+        context.outcome(scan, items)
+    }
 
-            documents.push(value);
-            json_values.push(json_value);
-        }
+    // Without an order to sort by, iteration stops once offset + limit documents matched
+    fn query_cf_with_options<T: DeserializeOwned + Serialize, U: DeserializeOwned>(
+        &self,
+        cf: &str,
+        query: &str,
+        options: &QueryOptions,
+    ) -> Result<Vec<KeyValuePair<U>>, KvStoreError> {
+        let planned = query::plan(query, &self.indexes.for_cf(cf))?;
+        let wanted = match options.order_by {
+            Some(_) => None,
+            None => options
+                .limit
+                .map(|limit| limit.saturating_add(options.offset)),
+        };
 
-        // Create JSON array and run query
-        let json_array = serde_json::Value::Array(json_values.clone());
+        let mut matches = Vec::new();
+        self.for_each_match::<T, _>(
+            cf,
+            options.keys.as_ref(),
+            &planned,
+            &options.context,
+            |pair, doc| {
+                matches.push((pair.key, doc));
+                wanted.is_none_or(|wanted| matches.len() < wanted)
+            },
+        )?;
+        if let Some((field, order)) = &options.order_by {
+            matches.sort_by(|(_, a), (_, b)| {
+                let ordering = query::compare_fields(a, b, field);
+                match order {
+                    SortOrder::Asc => ordering,
+                    SortOrder::Desc => ordering.reverse(),
+                }
+            });
+        }
 
-        let matches = match json_array.query(query) {
-            Ok(m) => m,
-            Err(e) => return Err(KvStoreError::InvalidQuery(format!("JSONPath error: {}", e))),
-        };
+        matches
+            .into_iter()
+            .skip(options.offset)
+            .take(options.limit.unwrap_or(usize::MAX))
+            .map(|(key, doc)| {
+                let doc = match &options.fields {
+                    Some(fields) => query::project(&doc, fields),
+                    None => doc,
+                };
+                let value = serde_json::from_value(doc)
+                    .map_err(|e| KvStoreError::DeserializationError(e.to_string()))?;
+                Ok(KeyValuePair { key, value })
+            })
+            .collect()
+    }
 
-        // Build result set by taking ownership of matching values
-        let mut final_results = Vec::with_capacity(matches.len());
+    fn query_cf_with_context<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        query: &str,
+        context: &QueryContext,
+    ) -> Result<QueryOutcome<T>, KvStoreError> {
+        let planned = query::plan(query, &self.indexes.for_cf(cf))?;
+        let mut items = Vec::new();
+        let scan = self.for_each_match::<T, _>(cf, None, &planned, context, |pair, _| {
+            items.push(pair);
+            true
+        });
+        context.outcome(scan, items)
+    }
 
-        for matched_value in matches {
-            for (i, json_value) in json_values.iter().enumerate() {
-                if matched_value == json_value {
-                    // Take ownership of the value using swap_remove - O(1) operation
-                    final_results.push(documents.swap_remove(i));
-                    json_values.swap_remove(i);
-                    break;
-                }
+    fn filter_cf<T, F>(
+        &self,
+        cf: &str,
+        mut predicate: F,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError>
+    where
+        T: DeserializeOwned,
+        F: FnMut(&str, &T) -> bool,
+    {
+        let mut results = Vec::new();
+        for item in self.iter_cf::<T, _>(cf, .., Direction::Forward)? {
+            let pair = item?;
+            if predicate(&pair.key, &pair.value) {
+                results.push(pair);
             }
         }
+        Ok(results)
+    }
 
-        Ok(final_results)
+    // MessagePack values are checked on the fields the predicate reads before
+    // being decoded into `T`
+    fn filter_cf_where<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        predicate: &Predicate,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
+        let mut results = Vec::new();
+        let mut iter = self.iter_cf::<T, _>(cf, .., Direction::Forward)?;
+        while let Some(item) = iter.next_raw() {
+            let (key, value) = item?;
+            if predicate.matches_raw(iter.serializer(), &value) == Some(false) {
+                continue;
+            }
+            let pair = iter.decode(&key, &value)?;
+            if predicate.matches(&pair.value) {
+                results.push(pair);
+            }
+        }
+        Ok(results)
     }
 
-    // Query implementation with key-value pairs
-    fn query_cf_with_keys<T: DeserializeOwned + Serialize>(
+    fn par_query_cf<T: DeserializeOwned + Serialize + Send>(
         &self,
         cf: &str,
         query: &str,
+        threads: usize,
     ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
-        let cf_handle = self
-            .db
-            .cf_handle(cf)
-            .ok_or_else(|| KvStoreError::InvalidColumnFamily(cf.to_string()))?;
-
-        // Collect all document data with keys
-        let mut documents_with_keys = Vec::new();
-        let mut json_values = Vec::new();
+        let outcome =
+            self.par_query_cf_with_context(cf, query, threads, &QueryContext::default())?;
+        Ok(outcome.items)
+    }
 
-        // Single pass collection of data
-        for item in self.db.iterator_cf(&cf_handle, IteratorMode::Start) {
-            let (key, value_bytes) = item?;
-            let value: T = MessagePackSerializer.deserialize(&value_bytes)?;
+    // Only full scans with a per-document filter are split; index scans and
+    // positional queries run as query_cf_with_context does. Workers share the
+    // scanned row count, and partial results hold what every range matched
+    // before stopping.
+    fn par_query_cf_with_context<T: DeserializeOwned + Serialize + Send>(
+        &self,
+        cf: &str,
+        query: &str,
+        threads: usize,
+        context: &QueryContext,
+    ) -> Result<QueryOutcome<T>, KvStoreError> {
+        let planned = query::plan(query, &self.indexes.for_cf(cf))?;
+        if threads <= 1 || planned.lookup.is_some() || !planned.is_per_document() {
+            return self.query_cf_with_context(cf, query, context);
+        }
 
-            // Convert to JSON for querying only once
-            let json_value = serde_json::to_value(&value)
-                .map_err(|e| KvStoreError::SerializationError(e.to_string()))?;
+        // Every range reads from the same snapshot, so the merged result is
+        // consistent even with concurrent writes
+        let snapshot = self.db.snapshot();
+        let ranges = self.split_cf(cf, threads)?;
+        let scanned = AtomicU64::new(0);
+        let (planned, snapshot, scanned) = (&planned, &snapshot, &scanned);
+        // split_cf returns at most `threads` ranges; grouping them keeps the
+        // thread count capped even if it returned more
+        let per_worker = ranges.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = ranges
+                .chunks(per_worker)
+                .map(|ranges| {
+                    scope.spawn(move || {
+                        let mut matches = Vec::new();
+                        let scan = ranges.iter().try_for_each(|range| {
+                            let mut opts = range.read_options();
+                            opts.set_snapshot(snapshot);
+                            let mut iter =
+                                self.typed_iter::<T>(cf, range.clone(), opts, Direction::Forward)?;
+                            while let Some(item) = iter.next_raw() {
+                                context.check(scanned.fetch_add(1, Ordering::Relaxed) + 1)?;
+                                let (key, value) = item?;
+                                if planned.rejects_raw(iter.serializer(), &value) {
+                                    continue;
+                                }
+                                let pair = iter.decode(&key, &value)?;
+                                if planned.select(to_json(&pair.value)?).is_some() {
+                                    matches.push(pair);
+                                }
+                            }
+                            Ok(())
+                        });
+                        // Matches found before an abort are kept for partial results
+                        (matches, scan)
+                    })
+                })
+                .collect();
+
+            // Ranges are consecutive, so joining them in order keeps key order
+            let mut items = Vec::new();
+            let mut scan = Ok(());
+            for worker in workers {
+                let (matches, worker_scan) = worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                items.extend(matches);
+                if scan.is_ok() {
+                    scan = worker_scan;
+                }
+            }
+            context.outcome(scan, items)
+        })
+    }
 
-            documents_with_keys.push((key.to_vec(), value));
-            json_values.push(json_value);
-        }
+    // Values-only range implementation
+    fn get_range_cf<T: DeserializeOwned, R: Into<KeyRange>>(
+        &self,
+        cf: &str,
+        range: R,
+        limit: usize,
+        direction: Direction,
+    ) -> Result<Vec<T>, KvStoreError> {
+        Ok(self
+            .get_range_cf_with_keys(cf, range, limit, direction)?
+            .into_iter()
+            .map(|pair| pair.value)
+            .collect())
+    }
 
-        // Create JSON array and run query
-        let json_array = serde_json::Value::Array(json_values.clone());
+    // Range implementation with key-value pairs
+    fn get_range_cf_with_keys<T: DeserializeOwned, R: Into<KeyRange>>(
+        &self,
+        cf: &str,
+        range: R,
+        limit: usize,
+        direction: Direction,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
+        Ok(self
+            .scan_range(cf, &range.into(), limit, direction)?
+            .into_iter()
+            .map(|(key, value)| KeyValuePair {
+                key: String::from_utf8_lossy(&key).into_owned(),
+                value,
+            })
+            .collect())
+    }
 
-        let matches = match json_array.query(query) {
-            Ok(m) => m,
-            Err(e) => return Err(KvStoreError::InvalidQuery(format!("JSONPath error: {}", e))),
-        };
+    fn page_cf<T: DeserializeOwned, R: Into<KeyRange>>(
+        &self,
+        cf: &str,
+        range: R,
+        page_size: usize,
+        direction: Direction,
+        token: Option<&PageToken>,
+    ) -> Result<Page<T>, KvStoreError> {
+        let page = self.page_cf_with_keys(cf, range, page_size, direction, token)?;
+        Ok(Page {
+            items: page.items.into_iter().map(|pair| pair.value).collect(),
+            next: page.next,
+        })
+    }
 
-        // Build result set by taking ownership of matching values
-        let mut final_results = Vec::with_capacity(matches.len());
+    // Resumes after the token's key with a seek instead of skipping earlier entries
+    fn page_cf_with_keys<T: DeserializeOwned, R: Into<KeyRange>>(
+        &self,
+        cf: &str,
+        range: R,
+        page_size: usize,
+        direction: Direction,
+        token: Option<&PageToken>,
+    ) -> Result<Page<KeyValuePair<T>>, KvStoreError> {
+        let mut range = range.into();
+        if let Some(token) = token {
+            if matches!(direction, Direction::Reverse)
+                != matches!(token.direction(), Direction::Reverse)
+            {
+                return Err(KvStoreError::InvalidQuery(
+                    "page token was issued for the opposite direction".to_string(),
+                ));
+            }
+            range = token.resume(range);
+            // A token past the end of the range resumes into nothing
+            if range.is_empty() {
+                return Ok(Page {
+                    items: Vec::new(),
+                    next: None,
+                });
+            }
+        }
 
-        for matched_value in matches {
-            for (i, json_value) in json_values.iter().enumerate() {
-                if matched_value == json_value {
-                    // Get key and value
-                    let (key, value) = documents_with_keys.swap_remove(i);
+        // Read one entry past the page to know whether another page follows
+        let mut entries = self.scan_range(cf, &range, page_size.saturating_add(1), direction)?;
+        let next = if entries.len() > page_size {
+            entries.truncate(page_size);
+            entries
+                .last()
+                .map(|(key, _)| PageToken::new(key.clone(), direction))
+        } else {
+            None
+        };
 
-                    // Convert key to string
-                    let key_str = String::from_utf8_lossy(&key).into_owned();
+        let items = entries
+            .into_iter()
+            .map(|(key, value)| KeyValuePair {
+                key: String::from_utf8_lossy(&key).into_owned(),
+                value,
+            })
+            .collect();
 
-                    // Create a KeyValuePair directly
-                    final_results.push(KeyValuePair {
-                        key: key_str,
-                        value,
-                    });
+        Ok(Page { items, next })
+    }
 
-                    // Remove from json values to avoid duplicate matches
-                    json_values.swap_remove(i);
-                    break;
-                }
+    fn scan_prefix_cf<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        prefix: &str,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
+        let range = KeyRange::prefix(prefix);
+        let mut opts = range.read_options();
+
+        // Prefix seeks can use the bloom filters, but only when the scanned
+        // prefix is exactly what the extractor produces for its keys
+        let extractor = self
+            .prefix_extractors
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cf)
+            .copied();
+        if let Some(extractor) = extractor {
+            if extractor.prefix_of(prefix.as_bytes()) == Some(prefix.as_bytes()) {
+                opts.set_total_order_seek(false);
+                opts.set_prefix_same_as_start(true);
             }
         }
 
-        Ok(final_results)
+        Ok(self
+            .scan_range_opt(cf, &range, opts, usize::MAX, Direction::Forward)?
+            .into_iter()
+            .map(|(key, value)| KeyValuePair {
+                key: String::from_utf8_lossy(&key).into_owned(),
+                value,
+            })
+            .collect())
     }
 
-    // Values-only range implementation
-    fn get_range_cf<T: DeserializeOwned + Serialize>(
+    fn iter_cf<T: DeserializeOwned, R: Into<KeyRange>>(
         &self,
         cf: &str,
-        from: &str,
-        to: &str,
-        limit: usize,
+        range: R,
         direction: Direction,
-    ) -> Result<Vec<T>, KvStoreError> {
-        let cf_handle = self.cf_handle(cf)?;
-        let iter = self.db.iterator_cf(&cf_handle, IteratorMode::Start);
-        let all_keys: Vec<Vec<u8>> = iter
-            .map(|r| r.map(|(k, _)| k.to_vec()))
-            .collect::<Result<_, _>>()?;
-
-        let from_idx = from.parse::<usize>().unwrap_or(0);
-        let to_idx = to.parse::<usize>().unwrap_or(all_keys.len());
-        let from_idx = from_idx.min(all_keys.len());
-        let to_idx = (to_idx + 1).min(all_keys.len());
-
-        let keys_to_fetch = match direction {
-            Direction::Forward => all_keys[from_idx..to_idx].to_vec(),
-            Direction::Reverse => {
-                let mut keys = all_keys[from_idx..to_idx].to_vec();
-                keys.reverse();
-                keys
+    ) -> Result<TypedIter<'_, T>, KvStoreError> {
+        let range = range.into();
+        let opts = range.read_options();
+        self.typed_iter(cf, range, opts, direction)
+    }
+
+    // Indexes live in companion column families, which also store their
+    // definitions so reopening registers them again. Entries are only rebuilt
+    // when the stored definition is missing or differs.
+    fn create_index<T: DeserializeOwned + Serialize>(
+        &self,
+        cf: &str,
+        name: &str,
+        path: &str,
+    ) -> Result<(), KvStoreError> {
+        self.cf_handle(cf)?;
+        let index = Index::new(cf, name, path)?;
+        let index_cf = index.cf_name().to_string();
+        let definition = index.definition()?;
+
+        // Existing entries are kept only if they were fully built for the same path
+        let current = match self.db.cf_handle(&index_cf) {
+            Some(handle) => {
+                self.db.get_cf(&handle, index::DEFINITION_KEY)?.as_deref()
+                    == Some(definition.as_slice())
             }
+            None => false,
         };
+        if !current {
+            if self.cf_exists(&index_cf) {
+                self.db.drop_cf(&index_cf)?;
+            }
+            self.db.create_cf(&index_cf, &Options::default())?;
+        }
+        // Registered first so writers wait for the rebuild instead of skipping
+        // it; queries ignore the index until it is marked built
+        if current {
+            index.mark_built();
+            self.indexes.add(cf, index);
+            return Ok(());
+        }
+        self.indexes.add(cf, index);
 
-        let mut results = Vec::with_capacity(limit.min(keys_to_fetch.len()));
-        for key in keys_to_fetch.iter().take(limit) {
-            if let Some(value) = self.db.get_cf(&cf_handle, key)? {
-                let value: T = MessagePackSerializer.deserialize(&value)?;
-                results.push(value);
+        let index = self
+            .indexes
+            .get(cf, name)
+            .ok_or_else(|| KvStoreError::InvalidQuery(format!("index {} was dropped", name)))?;
+        let _guard = self.indexes.lock();
+        let mut batch = WriteBatch::default();
+        let mut staged = HashMap::new();
+        for item in self.iter_cf::<T, _>(cf, .., Direction::Forward)? {
+            let pair = item?;
+            let doc = to_json(&pair.value)?;
+            self.stage_index_update(&mut batch, &index, &pair.key, Some(&doc), &mut staged)?;
+            if batch.len() >= WRITE_BATCH_SIZE {
+                self.db.write(std::mem::take(&mut batch))?;
+                staged.clear();
             }
         }
+        // Written last, so an interrupted rebuild is redone rather than trusted
+        let index_cf = self.cf_handle(&index_cf)?;
+        batch.put_cf(&index_cf, index::DEFINITION_KEY, definition);
+        self.db.write(batch)?;
+        index.mark_built();
+        Ok(())
+    }
 
-        Ok(results)
+    fn drop_index(&self, cf: &str, name: &str) -> Result<(), KvStoreError> {
+        let index = self.indexes.remove(cf, name).ok_or_else(|| {
+            KvStoreError::InvalidQuery(format!("no index named {} on {}", name, cf))
+        })?;
+        self.db.drop_cf(index.cf_name()).map_err(KvStoreError::from)
     }
 
-    // Range implementation with key-value pairs
-    fn get_range_cf_with_keys<T: DeserializeOwned + Serialize>(
+    fn find_by_index_cf<T: DeserializeOwned, V: Serialize>(
         &self,
         cf: &str,
-        from: &str,
-        to: &str,
-        limit: usize,
-        direction: Direction,
+        index: &str,
+        value: &V,
     ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
-        let cf_handle = self.cf_handle(cf)?;
-        let iter = self.db.iterator_cf(&cf_handle, IteratorMode::Start);
-        let all_keys: Vec<Vec<u8>> = iter
-            .map(|r| r.map(|(k, _)| k.to_vec()))
-            .collect::<Result<_, _>>()?;
-
-        let from_idx = from.parse::<usize>().unwrap_or(0);
-        let to_idx = to.parse::<usize>().unwrap_or(all_keys.len());
-        let from_idx = from_idx.min(all_keys.len());
-        let to_idx = (to_idx + 1).min(all_keys.len());
-
-        let keys_to_fetch = match direction {
-            Direction::Forward => all_keys[from_idx..to_idx].to_vec(),
-            Direction::Reverse => {
-                let mut keys = all_keys[from_idx..to_idx].to_vec();
-                keys.reverse();
-                keys
-            }
-        };
+        self.find_by_index_range_cf::<T, V, _>(
+            cf,
+            index,
+            (Bound::Included(value), Bound::Included(value)),
+        )
+    }
+
+    fn find_by_index_range_cf<T: DeserializeOwned, V: Serialize, R: RangeBounds<V>>(
+        &self,
+        cf: &str,
+        index: &str,
+        range: R,
+    ) -> Result<Vec<KeyValuePair<T>>, KvStoreError> {
+        let keys = self.index_lookup(cf, index, &range)?;
+        self.fetch_pairs(cf, keys)
+    }
 
-        let mut results = Vec::with_capacity(limit.min(keys_to_fetch.len()));
-        for key in keys_to_fetch.iter().take(limit) {
-            if let Some(value) = self.db.get_cf(&cf_handle, key)? {
-                let value: T = MessagePackSerializer.deserialize(&value)?;
-                let key_str = String::from_utf8_lossy(key).into_owned();
+    fn explain_query(&self, cf: &str, query: &str) -> Result<QueryPlan, KvStoreError> {
+        self.cf_handle(cf)?;
+        Ok(query::plan(query, &self.indexes.for_cf(cf))?.plan)
+    }
 
-                // Create a KeyValuePair directly
-                results.push(KeyValuePair {
-                    key: key_str,
-                    value,
-                });
+    // Documents are read as JSON values, never as the type they were stored from
+    fn aggregate_cf<R: DeserializeOwned>(
+        &self,
+        cf: &str,
+        aggregation: &Aggregation,
+    ) -> Result<BTreeMap<String, R>, KvStoreError> {
+        #[cfg(feature = "bincode")]
+        if self.cf_serializer(cf) == Format::Bincode {
+            return Err(KvStoreError::InvalidQuery(format!(
+                "cannot aggregate {}: bincode values can't be read without their type",
+                cf
+            )));
+        }
+
+        let mut accumulator = aggregation.accumulator();
+        match &aggregation.filter {
+            Some(filter) => {
+                let planned = query::plan(filter, &self.indexes.for_cf(cf))?;
+                let mut failed = None;
+                self.for_each_match::<serde_json::Value, _>(
+                    cf,
+                    None,
+                    &planned,
+                    &aggregation.context,
+                    |_, doc| match accumulator.add(&doc) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            failed = Some(e);
+                            false
+                        }
+                    },
+                )?;
+                if let Some(e) = failed {
+                    return Err(e);
+                }
+            }
+            None => {
+                let iter = self.iter_cf::<serde_json::Value, _>(cf, .., Direction::Forward)?;
+                for (scanned, item) in (1..).zip(iter) {
+                    aggregation.context.check(scanned)?;
+                    accumulator.add(&item?.value)?;
+                }
             }
         }
 
-        Ok(results)
+        accumulator
+            .finish()
+            .into_iter()
+            .map(|(group, value)| {
+                let value = serde_json::from_value(value)
+                    .map_err(|e| KvStoreError::DeserializationError(e.to_string()))?;
+                Ok((group, value))
+            })
+            .collect()
     }
 }
+
+fn to_json<T: Serialize>(value: &T) -> Result<serde_json::Value, KvStoreError> {
+    serde_json::to_value(value).map_err(|e| KvStoreError::SerializationError(e.to_string()))
+}
@@ -0,0 +1,181 @@
+use serde_json::{Map, Number, Value};
+
+/// Reads the top-level `fields` of a MessagePack-encoded map, skipping over
+/// every other field without decoding it. Returns `None` if the value is not
+/// a map or holds something JSON cannot represent, such as extension types.
+pub(crate) fn extract_fields(bytes: &[u8], fields: &[String]) -> Option<Value> {
+    let mut reader = Reader { bytes };
+    let marker = reader.byte()?;
+    let len = reader.map_len(marker)?;
+
+    let mut doc = Map::new();
+    for _ in 0..len {
+        let key = match reader.value()? {
+            Value::String(key) => key,
+            _ => return None,
+        };
+        if fields.contains(&key) {
+            doc.insert(key, reader.value()?);
+        } else {
+            reader.skip()?;
+        }
+    }
+    Some(Value::Object(doc))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&first, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(first)
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+
+    /// Big-endian length of `n` bytes.
+    fn len(&mut self, n: usize) -> Option<usize> {
+        let len = self
+            .take(n)?
+            .iter()
+            .fold(0u64, |len, &b| (len << 8) | u64::from(b));
+        usize::try_from(len).ok()
+    }
+
+    fn map_len(&mut self, marker: u8) -> Option<usize> {
+        match marker {
+            0x80..=0x8f => Some(usize::from(marker & 0x0f)),
+            0xde => self.len(2),
+            0xdf => self.len(4),
+            _ => None,
+        }
+    }
+
+    fn array_len(&mut self, marker: u8) -> Option<usize> {
+        match marker {
+            0x90..=0x9f => Some(usize::from(marker & 0x0f)),
+            0xdc => self.len(2),
+            0xdd => self.len(4),
+            _ => None,
+        }
+    }
+
+    /// Length of the string or binary payload following `marker`.
+    fn bytes_len(&mut self, marker: u8) -> Option<usize> {
+        match marker {
+            0xa0..=0xbf => Some(usize::from(marker & 0x1f)),
+            0xc4 | 0xd9 => self.len(1),
+            0xc5 | 0xda => self.len(2),
+            0xc6 | 0xdb => self.len(4),
+            _ => None,
+        }
+    }
+
+    /// Decodes one value into the JSON it would serialize to. Integer map
+    /// keys become strings, the same as with `serde_json`.
+    fn value(&mut self) -> Option<Value> {
+        let marker = self.byte()?;
+        let value = match marker {
+            0x00..=0x7f => Value::from(marker),
+            0xe0..=0xff => Value::from(marker as i8),
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xcc => Value::from(u8::from_be_bytes(self.array()?)),
+            0xcd => Value::from(u16::from_be_bytes(self.array()?)),
+            0xce => Value::from(u32::from_be_bytes(self.array()?)),
+            0xcf => Value::from(u64::from_be_bytes(self.array()?)),
+            0xd0 => Value::from(i8::from_be_bytes(self.array()?)),
+            0xd1 => Value::from(i16::from_be_bytes(self.array()?)),
+            0xd2 => Value::from(i32::from_be_bytes(self.array()?)),
+            0xd3 => Value::from(i64::from_be_bytes(self.array()?)),
+            0xca => float(f64::from(f32::from_be_bytes(self.array()?))),
+            0xcb => float(f64::from_be_bytes(self.array()?)),
+            0xa0..=0xbf | 0xd9..=0xdb => {
+                let len = self.bytes_len(marker)?;
+                Value::String(std::str::from_utf8(self.take(len)?).ok()?.to_string())
+            }
+            0xc4..=0xc6 => {
+                let len = self.bytes_len(marker)?;
+                Value::Array(self.take(len)?.iter().map(|&b| Value::from(b)).collect())
+            }
+            0x90..=0x9f | 0xdc | 0xdd => {
+                let len = self.array_len(marker)?;
+                let items = (0..len).map(|_| self.value()).collect::<Option<_>>()?;
+                Value::Array(items)
+            }
+            0x80..=0x8f | 0xde | 0xdf => {
+                let len = self.map_len(marker)?;
+                let mut map = Map::new();
+                for _ in 0..len {
+                    let key = match self.value()? {
+                        Value::String(key) => key,
+                        Value::Number(n) => n.to_string(),
+                        _ => return None,
+                    };
+                    map.insert(key, self.value()?);
+                }
+                Value::Object(map)
+            }
+            // Extension types
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Steps over one value, nested ones included, without decoding it.
+    fn skip(&mut self) -> Option<()> {
+        let mut pending = 1usize;
+        while pending > 0 {
+            pending -= 1;
+            let marker = self.byte()?;
+            let width = match marker {
+                0x00..=0x7f | 0xe0..=0xff | 0xc0 | 0xc2 | 0xc3 => 0,
+                0xcc | 0xd0 => 1,
+                0xcd | 0xd1 => 2,
+                0xca | 0xce | 0xd2 => 4,
+                0xcb | 0xcf | 0xd3 => 8,
+                0xd4 => 2,
+                0xd5 => 3,
+                0xd6 => 5,
+                0xd7 => 9,
+                0xd8 => 17,
+                0xa0..=0xbf | 0xc4..=0xc6 | 0xd9..=0xdb => self.bytes_len(marker)?,
+                0xc7 => self.len(1)? + 1,
+                0xc8 => self.len(2)? + 1,
+                0xc9 => self.len(4)? + 1,
+                0x90..=0x9f | 0xdc | 0xdd => {
+                    pending = pending.checked_add(self.array_len(marker)?)?;
+                    0
+                }
+                0x80..=0x8f | 0xde | 0xdf => {
+                    pending = pending.checked_add(self.map_len(marker)?.checked_mul(2)?)?;
+                    0
+                }
+                // 0xc1 is never used
+                _ => return None,
+            };
+            self.take(width)?;
+        }
+        Some(())
+    }
+}
+
+/// Non-finite floats become null, as they do in `serde_json`.
+fn float(f: f64) -> Value {
+    Number::from_f64(f).map_or(Value::Null, Value::Number)
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+
+use rocksdb::Direction;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::KvStoreError;
+use crate::range::KeyRange;
+
+const FORWARD: u8 = b'f';
+const REVERSE: u8 = b'r';
+
+/// Opaque continuation token pointing just past the last key of a page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PageToken {
+    last_key: Vec<u8>,
+    reverse: bool,
+}
+
+impl PageToken {
+    pub(crate) fn new(last_key: Vec<u8>, direction: Direction) -> Self {
+        PageToken {
+            last_key,
+            reverse: matches!(direction, Direction::Reverse),
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.reverse {
+            Direction::Reverse
+        } else {
+            Direction::Forward
+        }
+    }
+
+    /// Narrows `range` to the keys that come after this token in its direction.
+    /// The tighter bound wins, so a token from another range never widens it.
+    pub(crate) fn resume(&self, range: KeyRange) -> KeyRange {
+        let last_key = self.last_key.clone();
+        if self.reverse {
+            let end = match range.end {
+                Bound::Included(end) if end < last_key => Bound::Included(end),
+                Bound::Excluded(end) if end < last_key => Bound::Excluded(end),
+                _ => Bound::Excluded(last_key),
+            };
+            KeyRange {
+                start: range.start,
+                end,
+            }
+        } else {
+            let start = match range.start {
+                Bound::Included(start) if start > last_key => Bound::Included(start),
+                Bound::Excluded(start) if start > last_key => Bound::Excluded(start),
+                _ => Bound::Excluded(last_key),
+            };
+            KeyRange {
+                start,
+                end: range.end,
+            }
+        }
+    }
+}
+
+impl fmt::Display for PageToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = if self.reverse { REVERSE } else { FORWARD };
+        write!(f, "{:02x}{}", tag, hex::encode(&self.last_key))
+    }
+}
+
+impl FromStr for PageToken {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s)
+            .map_err(|e| KvStoreError::InvalidQuery(format!("bad page token: {}", e)))?;
+        let (tag, last_key) = bytes
+            .split_first()
+            .ok_or_else(|| KvStoreError::InvalidQuery("empty page token".to_string()))?;
+        let direction = match *tag {
+            FORWARD => Direction::Forward,
+            REVERSE => Direction::Reverse,
+            _ => return Err(KvStoreError::InvalidQuery("bad page token".to_string())),
+        };
+        Ok(PageToken::new(last_key.to_vec(), direction))
+    }
+}
+
+impl TryFrom<String> for PageToken {
+    type Error = KvStoreError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PageToken> for String {
+    fn from(token: PageToken) -> Self {
+        token.to_string()
+    }
+}
+
+/// One page of results plus the token for the next one, which is `None` once
+/// the range is exhausted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<PageToken>,
+}
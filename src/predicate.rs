@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+
+use serde::ser::{self, Serialize};
+use serde_json::Value;
+
+use crate::msgpack;
+use crate::query::{compare_values, field as lookup_field};
+use crate::schema::CfSerializer;
+
+/// Starts a predicate on the field at a dot-separated `path`, such as
+/// `owner.name`.
+pub fn field(path: &str) -> Field {
+    Field {
+        path: path.to_string(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    path: String,
+}
+
+impl Field {
+    pub fn eq<V: Into<Value>>(self, value: V) -> Predicate {
+        self.compare(Op::Eq, value)
+    }
+
+    pub fn ne<V: Into<Value>>(self, value: V) -> Predicate {
+        self.compare(Op::Ne, value)
+    }
+
+    pub fn gt<V: Into<Value>>(self, value: V) -> Predicate {
+        self.compare(Op::Gt, value)
+    }
+
+    pub fn ge<V: Into<Value>>(self, value: V) -> Predicate {
+        self.compare(Op::Ge, value)
+    }
+
+    pub fn lt<V: Into<Value>>(self, value: V) -> Predicate {
+        self.compare(Op::Lt, value)
+    }
+
+    pub fn le<V: Into<Value>>(self, value: V) -> Predicate {
+        self.compare(Op::Le, value)
+    }
+
+    pub fn exists(self) -> Predicate {
+        Predicate(Node::Exists(self.path))
+    }
+
+    fn compare<V: Into<Value>>(self, op: Op, value: V) -> Predicate {
+        Predicate(Node::Compare {
+            path: self.path,
+            op,
+            value: value.into(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+/// A condition on the fields of a document, built with `field`. Comparisons
+/// on a missing field are false; values of different types are never equal
+/// and never ordered.
+#[derive(Debug, Clone)]
+pub struct Predicate(Node);
+
+#[derive(Debug, Clone)]
+enum Node {
+    Compare { path: String, op: Op, value: Value },
+    Exists(String),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+}
+
+impl Predicate {
+    pub fn and(self, other: Predicate) -> Predicate {
+        Predicate(Node::And(Box::new(self.0), Box::new(other.0)))
+    }
+
+    pub fn or(self, other: Predicate) -> Predicate {
+        Predicate(Node::Or(Box::new(self.0), Box::new(other.0)))
+    }
+
+    /// Checks `value` without converting it to JSON: only the fields the
+    /// predicate refers to are visited while serializing it.
+    pub fn matches<T: Serialize>(&self, value: &T) -> bool {
+        let paths = self.paths();
+        let mut found = HashMap::new();
+        let extractor = Extractor {
+            prefix: "",
+            paths: &paths,
+            found: &mut found,
+        };
+        if value.serialize(extractor).is_err() {
+            return false;
+        }
+        self.eval(&|path| found.get(path))
+    }
+
+    /// Evaluates the predicate on stored `bytes`, decoding only the top-level
+    /// fields it reads. `None` if the value is not plain MessagePack or lacks
+    /// one of the fields, which decoding may still fill in.
+    pub(crate) fn matches_raw(&self, serializer: &CfSerializer, bytes: &[u8]) -> Option<bool> {
+        let paths = self.paths();
+        let mut fields: Vec<String> = paths
+            .iter()
+            .map(|path| path.split('.').next().unwrap_or(path).to_string())
+            .collect();
+        fields.dedup();
+        let doc = msgpack::extract_fields(serializer.msgpack_payload(bytes)?, &fields)?;
+        if !paths.iter().all(|path| lookup_field(&doc, path).is_some()) {
+            return None;
+        }
+        Some(self.eval(&|path| lookup_field(&doc, path)))
+    }
+
+    /// Every field path the predicate reads.
+    pub(crate) fn paths(&self) -> Vec<&str> {
+        let mut paths = Vec::new();
+        self.0.collect_paths(&mut paths);
+        paths.sort_unstable();
+        paths.dedup();
+        paths
+    }
+
+    /// Evaluates the predicate with `lookup` resolving field paths.
+    pub(crate) fn eval<'v>(&self, lookup: &impl Fn(&str) -> Option<&'v Value>) -> bool {
+        self.0.eval(lookup)
+    }
+}
+
+impl std::ops::Not for Predicate {
+    type Output = Predicate;
+
+    fn not(self) -> Predicate {
+        Predicate(Node::Not(Box::new(self.0)))
+    }
+}
+
+impl Node {
+    fn collect_paths<'a>(&'a self, paths: &mut Vec<&'a str>) {
+        match self {
+            Node::Compare { path, .. } | Node::Exists(path) => paths.push(path),
+            Node::And(a, b) | Node::Or(a, b) => {
+                a.collect_paths(paths);
+                b.collect_paths(paths);
+            }
+            Node::Not(inner) => inner.collect_paths(paths),
+        }
+    }
+
+    fn eval<'v>(&self, lookup: &impl Fn(&str) -> Option<&'v Value>) -> bool {
+        match self {
+            Node::Compare { path, op, value } => match lookup(path) {
+                Some(actual) => compare(*op, actual, value),
+                None => false,
+            },
+            Node::Exists(path) => lookup(path).is_some(),
+            Node::And(a, b) => a.eval(lookup) && b.eval(lookup),
+            Node::Or(a, b) => a.eval(lookup) || b.eval(lookup),
+            Node::Not(inner) => !inner.eval(lookup),
+        }
+    }
+}
+
+fn compare(op: Op, actual: &Value, expected: &Value) -> bool {
+    let comparable = matches!(
+        (actual, expected),
+        (Value::Null, Value::Null)
+            | (Value::Bool(_), Value::Bool(_))
+            | (Value::Number(_), Value::Number(_))
+            | (Value::String(_), Value::String(_))
+    );
+    if !comparable {
+        return match op {
+            Op::Eq => actual == expected,
+            Op::Ne => actual != expected,
+            _ => false,
+        };
+    }
+
+    let ordering = compare_values(Some(actual), Some(expected));
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+    }
+}
+
+/// Serializer that records the values found at `paths` and skips every
+/// field that is not on the way to one of them.
+struct Extractor<'a> {
+    prefix: &'a str,
+    paths: &'a [&'a str],
+    found: &'a mut HashMap<String, Value>,
+}
+
+impl Extractor<'_> {
+    fn visit<T: Serialize + ?Sized>(
+        &mut self,
+        name: &str,
+        value: &T,
+    ) -> Result<(), serde_json::Error> {
+        let path = if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.prefix, name)
+        };
+        if self.paths.contains(&path.as_str()) {
+            self.found.insert(path, serde_json::to_value(value)?);
+        } else if self.paths.iter().any(|wanted| {
+            wanted
+                .strip_prefix(path.as_str())
+                .is_some_and(|rest| rest.starts_with('.'))
+        }) {
+            value.serialize(Extractor {
+                prefix: &path,
+                paths: self.paths,
+                found: &mut *self.found,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for Extractor<'a> {
+    type Ok = ();
+    type Error = serde_json::Error;
+    type SerializeSeq = Skip;
+    type SerializeTuple = Skip;
+    type SerializeTupleStruct = Skip;
+    type SerializeTupleVariant = Skip;
+    type SerializeMap = Fields<'a>;
+    type SerializeStruct = Fields<'a>;
+    type SerializeStructVariant = Skip;
+
+    fn serialize_bool(self, _: bool) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_i8(self, _: i8) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_i16(self, _: i16) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_i32(self, _: i32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_i64(self, _: i64) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_u8(self, _: u8) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_u16(self, _: u16) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_u32(self, _: u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_u64(self, _: u64) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_f32(self, _: f32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_f64(self, _: f64) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_char(self, _: char) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_str(self, _: &str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        mut self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        // Externally tagged, like `{"Variant": value}` in JSON
+        self.visit(variant, value)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Skip, Self::Error> {
+        Ok(Skip)
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Skip, Self::Error> {
+        Ok(Skip)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Skip, Self::Error> {
+        Ok(Skip)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Skip, Self::Error> {
+        Ok(Skip)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Fields<'a>, Self::Error> {
+        Ok(Fields {
+            extractor: self,
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Fields<'a>, Self::Error> {
+        Ok(Fields {
+            extractor: self,
+            key: None,
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Skip, Self::Error> {
+        Ok(Skip)
+    }
+}
+
+struct Fields<'a> {
+    extractor: Extractor<'a>,
+    key: Option<String>,
+}
+
+impl ser::SerializeStruct for Fields<'_> {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.extractor.visit(name, value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for Fields<'_> {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.key = match serde_json::to_value(key)? {
+            Value::String(key) => Some(key),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        match self.key.take() {
+            Some(key) => self.extractor.visit(&key, value),
+            None => Ok(()),
+        }
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Compound serializer for values no path leads into.
+struct Skip;
+
+impl ser::SerializeSeq for Skip {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, _: &T) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for Skip {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, _: &T) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for Skip {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &T) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for Skip {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &T) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for Skip {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
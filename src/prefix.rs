@@ -0,0 +1,122 @@
+use rocksdb::{BlockBasedOptions, Options, SliceTransform};
+
+/// Column family recording, under each column family's name, the name of the
+/// prefix extractor it was created with. Extractors can't be rebuilt from
+/// what is stored, so it is only used to check they are passed again when
+/// the database is reopened.
+pub(crate) const PREFIX_CF: &str = "__prefix_extractors__";
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Fixed(usize),
+    Delimited {
+        delimiter: u8,
+        count: usize,
+        transform: fn(&[u8]) -> &[u8],
+        in_domain: fn(&[u8]) -> bool,
+    },
+}
+
+/// How a column family derives key prefixes. RocksDB uses the prefix for
+/// prefix bloom filters and prefix seeks, which lets `scan_prefix_cf` skip
+/// files and memtable entries that cannot contain the prefix.
+#[derive(Debug, Clone, Copy)]
+pub struct PrefixExtractor {
+    kind: Kind,
+    bloom_bits_per_key: Option<f64>,
+}
+
+impl PrefixExtractor {
+    /// Uses the first `len` bytes of every key; shorter keys have no prefix.
+    pub fn fixed(len: usize) -> Self {
+        PrefixExtractor {
+            kind: Kind::Fixed(len),
+            bloom_bits_per_key: None,
+        }
+    }
+
+    /// Uses everything up to and including the `COUNT`th `DELIMITER`, so
+    /// `delimited::<b':', 2>()` maps `room:7:player:3` to `room:7:`. Keys with
+    /// fewer delimiters have no prefix. RocksDB only accepts plain function
+    /// pointers as extractors, which is why both are const parameters.
+    pub fn delimited<const DELIMITER: u8, const COUNT: usize>() -> Self {
+        PrefixExtractor {
+            kind: Kind::Delimited {
+                delimiter: DELIMITER,
+                count: COUNT,
+                transform: delimited_prefix::<DELIMITER, COUNT>,
+                in_domain: has_delimited_prefix::<DELIMITER, COUNT>,
+            },
+            bloom_bits_per_key: None,
+        }
+    }
+
+    /// Builds prefix bloom filters for SST files and the memtable.
+    pub fn with_bloom_filter(mut self, bits_per_key: f64) -> Self {
+        self.bloom_bits_per_key = Some(bits_per_key);
+        self
+    }
+
+    /// Identifies how prefixes are extracted; bloom filter settings don't
+    /// affect it.
+    pub(crate) fn name(&self) -> String {
+        match self.kind {
+            Kind::Fixed(len) => format!("fixed.{}", len),
+            Kind::Delimited {
+                delimiter, count, ..
+            } => format!("delimited.{}.{}", delimiter, count),
+        }
+    }
+
+    /// The prefix RocksDB extracts from `key`, if the key has one.
+    pub(crate) fn prefix_of<'k>(&self, key: &'k [u8]) -> Option<&'k [u8]> {
+        match self.kind {
+            Kind::Fixed(len) => key.get(..len),
+            Kind::Delimited {
+                transform,
+                in_domain,
+                ..
+            } => in_domain(key).then(|| transform(key)),
+        }
+    }
+
+    pub(crate) fn options(&self) -> Options {
+        let mut opts = Options::default();
+        let transform = match self.kind {
+            Kind::Fixed(len) => SliceTransform::create_fixed_prefix(len),
+            Kind::Delimited {
+                delimiter,
+                count,
+                transform,
+                in_domain,
+            } => SliceTransform::create(
+                format!("rocksdb_client.delimited.{}.{}", delimiter, count).as_str(),
+                transform,
+                Some(in_domain),
+            ),
+        };
+        opts.set_prefix_extractor(transform);
+
+        if let Some(bits_per_key) = self.bloom_bits_per_key {
+            let mut table_opts = BlockBasedOptions::default();
+            table_opts.set_bloom_filter(bits_per_key, false);
+            opts.set_block_based_table_factory(&table_opts);
+            opts.set_memtable_prefix_bloom_ratio(0.1);
+        }
+        opts
+    }
+}
+
+fn delimited_prefix<const DELIMITER: u8, const COUNT: usize>(key: &[u8]) -> &[u8] {
+    let end = key
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| **b == DELIMITER)
+        .nth(COUNT.saturating_sub(1))
+        .map_or(key.len(), |(i, _)| i + 1);
+    &key[..end]
+}
+
+fn has_delimited_prefix<const DELIMITER: u8, const COUNT: usize>(key: &[u8]) -> bool {
+    COUNT > 0 && key.iter().filter(|b| **b == DELIMITER).count() >= COUNT
+}
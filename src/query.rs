@@ -0,0 +1,604 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use jsonpath_rust::parser::model::{
+    Comparable, Comparison, Filter, FilterAtom, FnArg, JpQuery, Literal, Segment, Selector,
+    SingularQuery, SingularQuerySegment, Test, TestFunction,
+};
+use jsonpath_rust::parser::parse_json_path;
+use jsonpath_rust::query::js_path_process;
+use serde_json::{Map, Value};
+
+use crate::context::QueryContext;
+use crate::errors::KvStoreError;
+use crate::index::Index;
+use crate::key_filter::KeyFilter;
+use crate::msgpack;
+use crate::schema::CfSerializer;
+
+/// How `query_cf` answers a query, as reported by `explain_query`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryPlan {
+    /// Every document in the column family is deserialized and matched.
+    FullScan,
+    /// Candidates are read from `index` using the `lookup` predicates, then
+    /// checked against the `residual` ones.
+    IndexScan {
+        index: String,
+        lookup: String,
+        residual: Option<String>,
+    },
+}
+
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryPlan::FullScan => write!(f, "FULL SCAN"),
+            QueryPlan::IndexScan {
+                index,
+                lookup,
+                residual,
+            } => {
+                write!(f, "INDEX SCAN {} ({})", index, lookup)?;
+                if let Some(residual) = residual {
+                    write!(f, " FILTER {}", residual)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Key filtering, sorting, paging and projection for `query_cf_with_options`.
+/// Fields are dot-separated paths into the document, such as `owner.name`.
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    pub(crate) keys: Option<KeyFilter>,
+    pub(crate) order_by: Option<(String, SortOrder)>,
+    pub(crate) limit: Option<usize>,
+    pub(crate) offset: usize,
+    pub(crate) fields: Option<Vec<String>>,
+    pub(crate) context: QueryContext,
+}
+
+impl QueryOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only considers documents whose key matches `filter`.
+    pub fn keys(mut self, filter: KeyFilter) -> Self {
+        self.keys = Some(filter);
+        self
+    }
+
+    /// Sorts matches by `field`. Documents missing the field sort first, then
+    /// null, booleans, numbers, strings, arrays and objects.
+    pub fn order_by(mut self, field: &str, order: SortOrder) -> Self {
+        self.order_by = Some((field.to_string(), order));
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Returns only these fields of each document, keeping their nesting.
+    pub fn select<I, S>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.fields = Some(fields.into_iter().map(|f| f.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Limits the scan with `context`. Queries stopping early fail with
+    /// `KvStoreError::QueryAborted`, even if partial results are allowed.
+    pub fn context(mut self, context: QueryContext) -> Self {
+        self.context = context;
+        self
+    }
+}
+
+/// The index range an index scan reads.
+pub(crate) struct IndexLookup {
+    pub(crate) index: String,
+    pub(crate) start: Bound<Value>,
+    pub(crate) end: Bound<Value>,
+}
+
+pub(crate) struct PlannedQuery {
+    query: JpQuery,
+    per_document: bool,
+    fields: Option<Vec<String>>,
+    pub(crate) plan: QueryPlan,
+    pub(crate) lookup: Option<IndexLookup>,
+}
+
+impl PlannedQuery {
+    /// Whether the query is a single `$[?...]` filter, which matches each
+    /// document on its own.
+    pub(crate) fn is_per_document(&self) -> bool {
+        self.per_document
+    }
+
+    /// The top-level fields a per-document filter reads, when it reads
+    /// nothing else. Evaluating it on a document holding just these fields
+    /// gives the same answer as on the whole document.
+    pub(crate) fn filter_fields(&self) -> Option<&[String]> {
+        self.fields.as_deref()
+    }
+
+    /// Positions in `docs` of the documents the query selects, in result
+    /// order. Matches below the top level do not select a document.
+    pub(crate) fn positions(&self, docs: &Value) -> Result<Vec<usize>, KvStoreError> {
+        let found = js_path_process(&self.query, docs)
+            .map_err(|e| KvStoreError::InvalidQuery(format!("JSONPath error: {}", e)))?;
+        let mut positions: Vec<usize> = Vec::with_capacity(found.len());
+        for path in found.into_iter().map(|found| found.path()) {
+            let position = path
+                .strip_prefix("$[")
+                .and_then(|rest| rest.strip_suffix(']'))
+                .and_then(|i| i.parse().ok());
+            if let Some(position) = position {
+                if !positions.contains(&position) {
+                    positions.push(position);
+                }
+            }
+        }
+        Ok(positions)
+    }
+
+    /// Whether the stored `bytes` certainly do not match, found by reading
+    /// only the fields the filter uses straight from MessagePack. Values that
+    /// can't be read that way, or lack one of the fields, are never rejected
+    /// here: decoding may still fill a missing field in, e.g. from
+    /// `#[serde(default)]`.
+    pub(crate) fn rejects_raw(&self, serializer: &CfSerializer, bytes: &[u8]) -> bool {
+        let Some(fields) = self.filter_fields() else {
+            return false;
+        };
+        serializer
+            .msgpack_payload(bytes)
+            .and_then(|payload| msgpack::extract_fields(payload, fields))
+            .filter(|doc| doc.as_object().is_some_and(|doc| doc.len() == fields.len()))
+            .is_some_and(|doc| self.select(doc).is_none())
+    }
+
+    /// Hands `doc` back if it matches the whole query. Index candidates are
+    /// checked against every predicate, not just the residual ones, since
+    /// indexes also hold array elements and compare numbers as `f64`.
+    pub(crate) fn select(&self, doc: Value) -> Option<Value> {
+        let docs = Value::Array(vec![doc]);
+        let matched = js_path_process(&self.query, &docs).is_ok_and(|found| !found.is_empty());
+        match docs {
+            Value::Array(mut docs) if matched => docs.pop(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Field {
+    Name(String),
+    Index(i64),
+}
+
+impl Field {
+    fn name(name: &str) -> Self {
+        Field::Name(unquote(name).to_string())
+    }
+}
+
+enum Op {
+    Eq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+struct Predicate {
+    conjunct: usize,
+    path: Vec<Field>,
+    op: Op,
+    value: Value,
+}
+
+/// Picks the most selective index usable for a `$[?...]` query: equality
+/// beats a two-sided range, which beats a one-sided one. Only top-level
+/// `&&` terms comparing a field of the document with a literal are used.
+pub(crate) fn plan(query: &str, indexes: &[Arc<Index>]) -> Result<PlannedQuery, KvStoreError> {
+    let query = parse_json_path(query)
+        .map_err(|e| KvStoreError::InvalidQuery(format!("JSONPath error: {}", e)))?;
+    let full_scan = |query, per_document| PlannedQuery {
+        query,
+        per_document,
+        fields: None,
+        plan: QueryPlan::FullScan,
+        lookup: None,
+    };
+
+    let filter = match query.segments.as_slice() {
+        [Segment::Selector(Selector::Filter(filter))] => filter.clone(),
+        [Segment::Selectors(selectors)] => match selectors.as_slice() {
+            [Selector::Filter(filter)] => filter.clone(),
+            _ => return Ok(full_scan(query, false)),
+        },
+        _ => return Ok(full_scan(query, false)),
+    };
+    // `$` has to stay the whole column family, so these can't be evaluated
+    // one document at a time or narrowed by an index
+    if reads_root(&filter) {
+        return Ok(full_scan(query, false));
+    }
+    let mut fields = Vec::new();
+    let fields = collect_filter(&filter, &mut fields).map(|()| fields);
+    let conjuncts = match filter {
+        Filter::And(items) => items,
+        other => vec![other],
+    };
+    let predicates: Vec<Predicate> = conjuncts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, conjunct)| predicate(i, conjunct))
+        .collect();
+
+    let mut best: Option<(u8, &Index, Vec<&Predicate>)> = None;
+    // Indexes still being built would miss documents
+    for index in indexes.iter().filter(|index| index.is_built()) {
+        let Some(path) = index_path(index.path()) else {
+            continue;
+        };
+        let usable: Vec<&Predicate> = predicates.iter().filter(|p| p.path == path).collect();
+        let eq = usable.iter().find(|p| matches!(p.op, Op::Eq));
+        let lower = usable.iter().find(|p| matches!(p.op, Op::Gt | Op::Gte));
+        let upper = usable.iter().find(|p| matches!(p.op, Op::Lt | Op::Lte));
+
+        let (score, used) = match (eq, lower, upper) {
+            (Some(eq), _, _) => (3, vec![*eq]),
+            (None, Some(lower), Some(upper)) => (2, vec![*lower, *upper]),
+            (None, Some(bound), None) | (None, None, Some(bound)) => (1, vec![*bound]),
+            (None, None, None) => continue,
+        };
+        if best
+            .as_ref()
+            .is_none_or(|(best_score, _, _)| score > *best_score)
+        {
+            best = Some((score, index, used));
+        }
+    }
+
+    let Some((_, index, used)) = best else {
+        return Ok(PlannedQuery {
+            fields,
+            ..full_scan(query, true)
+        });
+    };
+
+    let mut start = Bound::Unbounded;
+    let mut end = Bound::Unbounded;
+    for predicate in &used {
+        let value = predicate.value.clone();
+        match predicate.op {
+            Op::Eq => {
+                start = Bound::Included(value.clone());
+                end = Bound::Included(value);
+            }
+            Op::Gt => start = Bound::Excluded(value),
+            Op::Gte => start = Bound::Included(value),
+            Op::Lt => end = Bound::Excluded(value),
+            Op::Lte => end = Bound::Included(value),
+        }
+    }
+
+    let join = |items: Vec<&Filter>| {
+        items
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>()
+            .join(" && ")
+    };
+    let used_conjuncts: Vec<usize> = used.iter().map(|p| p.conjunct).collect();
+    let lookup = join(used_conjuncts.iter().map(|&i| &conjuncts[i]).collect());
+    let residual: Vec<&Filter> = conjuncts
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !used_conjuncts.contains(i))
+        .map(|(_, f)| f)
+        .collect();
+    let residual = (!residual.is_empty()).then(|| join(residual));
+
+    Ok(PlannedQuery {
+        plan: QueryPlan::IndexScan {
+            index: index.name().to_string(),
+            lookup,
+            residual,
+        },
+        lookup: Some(IndexLookup {
+            index: index.name().to_string(),
+            start,
+            end,
+        }),
+        query,
+        per_document: true,
+        fields,
+    })
+}
+
+// Bracketed names keep their quotes in the parsed query
+fn unquote(name: &str) -> &str {
+    name.trim_matches(|c| c == '\'' || c == '"')
+}
+
+/// Adds the top-level fields `filter` reads to `fields`. Fails if it can
+/// also reach the root, the whole document or fields picked by wildcards or
+/// descendant segments.
+fn collect_filter(filter: &Filter, fields: &mut Vec<String>) -> Option<()> {
+    match filter {
+        Filter::Or(items) | Filter::And(items) => items
+            .iter()
+            .try_for_each(|item| collect_filter(item, fields)),
+        Filter::Atom(FilterAtom::Filter { expr, .. }) => collect_filter(expr, fields),
+        Filter::Atom(FilterAtom::Test { expr, .. }) => collect_test(expr, fields),
+        Filter::Atom(FilterAtom::Comparison(cmp)) => {
+            let (Comparison::Eq(left, right)
+            | Comparison::Ne(left, right)
+            | Comparison::Gt(left, right)
+            | Comparison::Gte(left, right)
+            | Comparison::Lt(left, right)
+            | Comparison::Lte(left, right)) = cmp.as_ref();
+            collect_comparable(left, fields)?;
+            collect_comparable(right, fields)
+        }
+    }
+}
+
+fn collect_comparable(comparable: &Comparable, fields: &mut Vec<String>) -> Option<()> {
+    match comparable {
+        Comparable::Literal(_) => Some(()),
+        Comparable::Function(function) => collect_function(function, fields),
+        Comparable::SingularQuery(SingularQuery::Current(segments)) => match segments.first()? {
+            SingularQuerySegment::Name(name) => add_field(name, fields),
+            SingularQuerySegment::Index(_) => None,
+        },
+        Comparable::SingularQuery(SingularQuery::Root(_)) => None,
+    }
+}
+
+fn collect_test(test: &Test, fields: &mut Vec<String>) -> Option<()> {
+    match test {
+        Test::RelQuery(segments) => match segments.first()? {
+            Segment::Selector(Selector::Name(name)) => add_field(name, fields),
+            Segment::Selectors(selectors) => {
+                selectors.iter().try_for_each(|selector| match selector {
+                    Selector::Name(name) => add_field(name, fields),
+                    _ => None,
+                })
+            }
+            _ => None,
+        },
+        Test::AbsQuery(_) => None,
+        Test::Function(function) => collect_function(function, fields),
+    }
+}
+
+fn collect_function(function: &TestFunction, fields: &mut Vec<String>) -> Option<()> {
+    let args: Vec<&FnArg> = match function {
+        TestFunction::Custom(_, args) => args.iter().collect(),
+        TestFunction::Length(arg) => vec![arg],
+        TestFunction::Value(arg) | TestFunction::Count(arg) => vec![arg],
+        TestFunction::Search(a, b) | TestFunction::Match(a, b) => vec![a, b],
+    };
+    args.into_iter().try_for_each(|arg| match arg {
+        FnArg::Literal(_) => Some(()),
+        FnArg::Test(test) => collect_test(test, fields),
+        FnArg::Filter(filter) => collect_filter(filter, fields),
+    })
+}
+
+fn add_field(name: &str, fields: &mut Vec<String>) -> Option<()> {
+    let name = unquote(name);
+    if !fields.iter().any(|field| field == name) {
+        fields.push(name.to_string());
+    }
+    Some(())
+}
+
+/// Whether `filter` refers to the root anywhere, including inside function
+/// arguments and nested filters.
+fn reads_root(filter: &Filter) -> bool {
+    match filter {
+        Filter::Or(items) | Filter::And(items) => items.iter().any(reads_root),
+        Filter::Atom(FilterAtom::Filter { expr, .. }) => reads_root(expr),
+        Filter::Atom(FilterAtom::Test { expr, .. }) => test_reads_root(expr),
+        Filter::Atom(FilterAtom::Comparison(cmp)) => {
+            let (Comparison::Eq(left, right)
+            | Comparison::Ne(left, right)
+            | Comparison::Gt(left, right)
+            | Comparison::Gte(left, right)
+            | Comparison::Lt(left, right)
+            | Comparison::Lte(left, right)) = cmp.as_ref();
+            comparable_reads_root(left) || comparable_reads_root(right)
+        }
+    }
+}
+
+fn comparable_reads_root(comparable: &Comparable) -> bool {
+    match comparable {
+        Comparable::Literal(_) | Comparable::SingularQuery(SingularQuery::Current(_)) => false,
+        Comparable::SingularQuery(SingularQuery::Root(_)) => true,
+        Comparable::Function(function) => function_reads_root(function),
+    }
+}
+
+fn test_reads_root(test: &Test) -> bool {
+    match test {
+        Test::RelQuery(segments) => segments.iter().any(segment_reads_root),
+        Test::AbsQuery(_) => true,
+        Test::Function(function) => function_reads_root(function),
+    }
+}
+
+fn function_reads_root(function: &TestFunction) -> bool {
+    let args: Vec<&FnArg> = match function {
+        TestFunction::Custom(_, args) => args.iter().collect(),
+        TestFunction::Length(arg) => vec![arg],
+        TestFunction::Value(arg) | TestFunction::Count(arg) => vec![arg],
+        TestFunction::Search(a, b) | TestFunction::Match(a, b) => vec![a, b],
+    };
+    args.into_iter().any(|arg| match arg {
+        FnArg::Literal(_) => false,
+        FnArg::Test(test) => test_reads_root(test),
+        FnArg::Filter(filter) => reads_root(filter),
+    })
+}
+
+fn segment_reads_root(segment: &Segment) -> bool {
+    let reads = |selector: &Selector| matches!(selector, Selector::Filter(f) if reads_root(f));
+    match segment {
+        Segment::Descendant(inner) => segment_reads_root(inner),
+        Segment::Selector(selector) => reads(selector),
+        Segment::Selectors(selectors) => selectors.iter().any(reads),
+    }
+}
+
+/// Reads `@.field <op> literal`, or the mirrored form, as an index predicate.
+fn predicate(conjunct: usize, filter: &Filter) -> Option<Predicate> {
+    let Filter::Atom(FilterAtom::Comparison(cmp)) = filter else {
+        return None;
+    };
+    let (op, left, right) = match cmp.as_ref() {
+        Comparison::Eq(l, r) => (Op::Eq, l, r),
+        Comparison::Gt(l, r) => (Op::Gt, l, r),
+        Comparison::Gte(l, r) => (Op::Gte, l, r),
+        Comparison::Lt(l, r) => (Op::Lt, l, r),
+        Comparison::Lte(l, r) => (Op::Lte, l, r),
+        Comparison::Ne(_, _) => return None,
+    };
+    let (op, segments, literal) = match (left, right) {
+        (Comparable::SingularQuery(SingularQuery::Current(segs)), Comparable::Literal(lit)) => {
+            (op, segs, lit)
+        }
+        (Comparable::Literal(lit), Comparable::SingularQuery(SingularQuery::Current(segs))) => {
+            let flipped = match op {
+                Op::Eq => Op::Eq,
+                Op::Gt => Op::Lt,
+                Op::Gte => Op::Lte,
+                Op::Lt => Op::Gt,
+                Op::Lte => Op::Gte,
+            };
+            (flipped, segs, lit)
+        }
+        _ => return None,
+    };
+
+    let path = segments
+        .iter()
+        .map(|seg| match seg {
+            SingularQuerySegment::Name(name) => Field::name(name),
+            SingularQuerySegment::Index(i) => Field::Index(*i),
+        })
+        .collect();
+    let value = match literal {
+        Literal::Int(i) => Value::from(*i),
+        Literal::Float(f) => Value::from(*f),
+        Literal::String(s) => Value::from(s.as_str()),
+        Literal::Bool(b) => Value::from(*b),
+        Literal::Null => Value::Null,
+    };
+    Some(Predicate {
+        conjunct,
+        path,
+        op,
+        value,
+    })
+}
+
+/// The field an index covers, if its JSONPath is a plain `$.a.b[0]` chain.
+fn index_path(query: &JpQuery) -> Option<Vec<Field>> {
+    query
+        .segments
+        .iter()
+        .map(|seg| match seg {
+            Segment::Selector(Selector::Name(name)) => Some(Field::name(name)),
+            Segment::Selector(Selector::Index(i)) => Some(Field::Index(*i)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) fn field<'a>(doc: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(doc, |value, name| value.get(name))
+}
+
+pub(crate) fn compare_fields(a: &Value, b: &Value, path: &str) -> Ordering {
+    compare_values(field(a, path), field(b, path))
+}
+
+/// Orders JSON values by type first (missing, null, booleans, numbers,
+/// strings, arrays, objects), then by value for scalars.
+pub(crate) fn compare_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(value: Option<&Value>) -> u8 {
+        match value {
+            None => 0,
+            Some(Value::Null) => 1,
+            Some(Value::Bool(_)) => 2,
+            Some(Value::Number(_)) => 3,
+            Some(Value::String(_)) => 4,
+            Some(Value::Array(_)) => 5,
+            Some(Value::Object(_)) => 6,
+        }
+    }
+
+    match (a, b) {
+        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+        (Some(Value::Number(a)), Some(Value::Number(b))) => {
+            let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+            a.total_cmp(&b)
+        }
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Copies the selected fields of `doc` into a new object, leaving out the
+/// ones it does not have.
+pub(crate) fn project(doc: &Value, fields: &[String]) -> Value {
+    let mut projected = Value::Object(Map::new());
+    for path in fields {
+        let Some(value) = field(doc, path) else {
+            continue;
+        };
+        let mut target = &mut projected;
+        let mut names = path.split('.').peekable();
+        while let Some(name) = names.next() {
+            let Value::Object(object) = target else {
+                break;
+            };
+            if names.peek().is_none() {
+                object.insert(name.to_string(), value.clone());
+                break;
+            }
+            target = object
+                .entry(name)
+                .or_insert_with(|| Value::Object(Map::new()));
+        }
+    }
+    projected
+}
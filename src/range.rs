@@ -0,0 +1,158 @@
+use std::ops::{Bound, Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
+
+use rocksdb::{Direction, IteratorMode, ReadOptions};
+
+/// Key bounds for range scans. Keys compare bytewise, the same way RocksDB
+/// orders them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+    pub start: Bound<Vec<u8>>,
+    pub end: Bound<Vec<u8>>,
+}
+
+impl KeyRange {
+    pub fn new(start: Bound<&str>, end: Bound<&str>) -> Self {
+        KeyRange {
+            start: start.map(|k| k.as_bytes().to_vec()),
+            end: end.map(|k| k.as_bytes().to_vec()),
+        }
+    }
+
+    pub fn all() -> Self {
+        KeyRange {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    /// Every key starting with `prefix`.
+    pub fn prefix(prefix: &str) -> Self {
+        let start = prefix.as_bytes().to_vec();
+        let end = match prefix_successor(&start) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        KeyRange {
+            start: Bound::Included(start),
+            end,
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        let after_start = match &self.start {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match &self.end {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// Whether no key can fall inside the range.
+    pub(crate) fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start >= end,
+            _ => false,
+        }
+    }
+
+    /// Read options that keep the iterator inside the range. RocksDB bounds
+    /// are inclusive below and exclusive above, so an inclusive end is turned
+    /// into the smallest key sorting after it.
+    pub(crate) fn read_options(&self) -> ReadOptions {
+        let mut opts = ReadOptions::default();
+        // Column families with a prefix extractor would otherwise only guarantee
+        // order within the seek key's prefix
+        opts.set_total_order_seek(true);
+        match &self.start {
+            Bound::Included(start) | Bound::Excluded(start) => {
+                opts.set_iterate_lower_bound(start.clone())
+            }
+            Bound::Unbounded => {}
+        }
+        match &self.end {
+            Bound::Included(end) => {
+                let mut upper = end.clone();
+                upper.push(0);
+                opts.set_iterate_upper_bound(upper);
+            }
+            Bound::Excluded(end) => opts.set_iterate_upper_bound(end.clone()),
+            Bound::Unbounded => {}
+        }
+        opts
+    }
+
+    /// Where to seek to start iterating in `direction`. Reverse scans start
+    /// from the end, which RocksDB clamps to the upper bound set in
+    /// `read_options`.
+    pub(crate) fn iterator_mode(&self, direction: Direction) -> IteratorMode<'_> {
+        match (direction, &self.start) {
+            (Direction::Forward, Bound::Included(start) | Bound::Excluded(start)) => {
+                IteratorMode::From(start, Direction::Forward)
+            }
+            (Direction::Forward, Bound::Unbounded) => IteratorMode::Start,
+            (Direction::Reverse, _) => IteratorMode::End,
+        }
+    }
+}
+
+/// The first key past every key starting with `prefix`: trailing 0xFF bytes
+/// are dropped and the last remaining byte bumped. `None` if there is no such key.
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&u8::MAX) {
+        end.pop();
+    }
+    *end.last_mut()? += 1;
+    Some(end)
+}
+
+impl From<RangeFull> for KeyRange {
+    fn from(_: RangeFull) -> Self {
+        KeyRange::all()
+    }
+}
+
+impl From<Range<&str>> for KeyRange {
+    fn from(range: Range<&str>) -> Self {
+        KeyRange::new(Bound::Included(range.start), Bound::Excluded(range.end))
+    }
+}
+
+impl From<RangeInclusive<&str>> for KeyRange {
+    fn from(range: RangeInclusive<&str>) -> Self {
+        KeyRange::new(Bound::Included(range.start()), Bound::Included(range.end()))
+    }
+}
+
+impl From<RangeFrom<&str>> for KeyRange {
+    fn from(range: RangeFrom<&str>) -> Self {
+        KeyRange::new(Bound::Included(range.start), Bound::Unbounded)
+    }
+}
+
+impl From<RangeTo<&str>> for KeyRange {
+    fn from(range: RangeTo<&str>) -> Self {
+        KeyRange::new(Bound::Unbounded, Bound::Excluded(range.end))
+    }
+}
+
+impl From<RangeToInclusive<&str>> for KeyRange {
+    fn from(range: RangeToInclusive<&str>) -> Self {
+        KeyRange::new(Bound::Unbounded, Bound::Included(range.end))
+    }
+}
+
+impl From<(Bound<&str>, Bound<&str>)> for KeyRange {
+    fn from((start, end): (Bound<&str>, Bound<&str>)) -> Self {
+        KeyRange::new(start, end)
+    }
+}
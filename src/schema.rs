@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::errors::KvStoreError;
+use crate::serializer::{ByteSerializer, Codec, Format};
+
+// Shares the 0xC1 marker with the compression header; 0xEE keeps the two apart.
+// Only MessagePack and JSON values can be told apart from an envelope, so
+// other formats envelope every value.
+const ENVELOPE_MAGIC: [u8; 2] = [0xC1, 0xEE];
+const ENVELOPE_LEN: usize = 7;
+
+type Migration = Arc<dyn Fn(&Codec, &[u8]) -> Result<Vec<u8>, KvStoreError> + Send + Sync>;
+
+/// Current schema version of the values in a column family, together with the
+/// upgrade steps that turn older values into the current shape.
+///
+/// Values written before a schema was registered are treated as version 0.
+/// With MessagePack and JSON they carry no envelope; other formats always
+/// write one. Once a schema is registered every value written is enveloped,
+/// so JSON column families no longer hold plain JSON that other tools can
+/// read.
+#[derive(Clone)]
+pub struct Schema {
+    version: u32,
+    migrations: BTreeMap<u32, Migration>,
+}
+
+impl Schema {
+    pub fn new(version: u32) -> Self {
+        Schema {
+            version,
+            migrations: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the step that upgrades values stored at `from_version` to
+    /// `from_version + 1`.
+    pub fn migration<From, To, F>(mut self, from_version: u32, upgrade: F) -> Self
+    where
+        From: DeserializeOwned,
+        To: Serialize,
+        F: Fn(From) -> To + Send + Sync + 'static,
+    {
+        let step: Migration = Arc::new(move |codec: &Codec, bytes: &[u8]| {
+            let old: From = codec.deserialize(bytes)?;
+            codec.serialize(&upgrade(old))
+        });
+        self.migrations.insert(from_version, step);
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn upgrade(
+        &self,
+        codec: &Codec,
+        from_version: u32,
+        bytes: &[u8],
+    ) -> Result<Vec<u8>, KvStoreError> {
+        if from_version > self.version {
+            return Err(KvStoreError::MigrationError(format!(
+                "value has schema version {} but the current version is {}",
+                from_version, self.version
+            )));
+        }
+
+        let mut bytes = bytes.to_vec();
+        for version in from_version..self.version {
+            let step = self.migrations.get(&version).ok_or_else(|| {
+                KvStoreError::MigrationError(format!(
+                    "no migration registered from schema version {}",
+                    version
+                ))
+            })?;
+            bytes = step(codec, &bytes)?;
+        }
+        Ok(bytes)
+    }
+}
+
+struct Envelope<'a> {
+    format: u8,
+    version: u32,
+    payload: &'a [u8],
+}
+
+fn open_envelope(bytes: &[u8]) -> Option<Envelope<'_>> {
+    if bytes.len() < ENVELOPE_LEN || bytes[..2] != ENVELOPE_MAGIC {
+        return None;
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&bytes[3..ENVELOPE_LEN]);
+    Some(Envelope {
+        format: bytes[2],
+        version: u32::from_le_bytes(version),
+        payload: &bytes[ENVELOPE_LEN..],
+    })
+}
+
+fn seal_envelope(format: Format, version: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(ENVELOPE_LEN + payload.len());
+    out.extend_from_slice(&ENVELOPE_MAGIC);
+    out.push(format.id());
+    out.extend_from_slice(&version.to_le_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Serializer for a single column family: its codec plus the registered schema,
+/// if any. Writes are enveloped when a schema is present or the format cannot
+/// be told apart from an envelope.
+#[derive(Clone)]
+pub(crate) struct CfSerializer {
+    codec: Codec,
+    schema: Option<Arc<Schema>>,
+}
+
+impl CfSerializer {
+    pub(crate) fn new(codec: Codec, schema: Option<Arc<Schema>>) -> Self {
+        CfSerializer { codec, schema }
+    }
+
+    /// Whether values can be recognised as unenveloped, which needs a format
+    /// that never starts with the envelope's 0xC1 marker.
+    fn sniffs_envelope(&self) -> bool {
+        self.codec.format().reserves_marker_byte()
+    }
+
+    /// Splits a stored value into the codec it was written with, its schema
+    /// version and the encoded payload.
+    fn unpack<'a>(&self, bytes: &'a [u8]) -> Result<(Codec, u32, &'a [u8]), KvStoreError> {
+        let sniffs = self.sniffs_envelope();
+        if sniffs && self.schema.is_none() {
+            return Ok((self.codec, 0, bytes));
+        }
+        match open_envelope(bytes) {
+            Some(envelope) => {
+                let format = Format::from_id(envelope.format).ok_or_else(|| {
+                    KvStoreError::DeserializationError(format!(
+                        "unsupported serializer format id: {}",
+                        envelope.format
+                    ))
+                })?;
+                Ok((
+                    self.codec.with_format(format),
+                    envelope.version,
+                    envelope.payload,
+                ))
+            }
+            None if sniffs => Ok((self.codec, 0, bytes)),
+            None => Err(KvStoreError::DeserializationError(
+                "value has no schema envelope".to_string(),
+            )),
+        }
+    }
+
+    /// The MessagePack payload of `bytes`, if it can be read as stored: plain
+    /// MessagePack, uncompressed and already at the current schema version.
+    pub(crate) fn msgpack_payload<'a>(&self, bytes: &'a [u8]) -> Option<&'a [u8]> {
+        let (codec, version, payload) = self.unpack(bytes).ok()?;
+        let current = self
+            .schema
+            .as_ref()
+            .is_none_or(|schema| schema.version == version);
+        (current && matches!(codec, Codec::Plain(Format::MessagePack))).then_some(payload)
+    }
+
+    /// Rewrites `bytes` at the current schema version, or returns `None` when
+    /// the value is already current or no schema is registered.
+    pub(crate) fn upgrade(&self, bytes: &[u8]) -> Result<Option<Vec<u8>>, KvStoreError> {
+        let Some(schema) = &self.schema else {
+            return Ok(None);
+        };
+        let (codec, version, payload) = self.unpack(bytes)?;
+        if version == schema.version {
+            return Ok(None);
+        }
+        let upgraded = schema.upgrade(&codec, version, payload)?;
+        Ok(Some(seal_envelope(
+            codec.format(),
+            schema.version,
+            &upgraded,
+        )))
+    }
+}
+
+impl ByteSerializer for CfSerializer {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
+        let payload = self.codec.serialize(value)?;
+        match &self.schema {
+            Some(schema) => Ok(seal_envelope(self.codec.format(), schema.version, &payload)),
+            None if self.sniffs_envelope() => Ok(payload),
+            None => Ok(seal_envelope(self.codec.format(), 0, &payload)),
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
+        let (codec, version, payload) = self.unpack(bytes)?;
+        match &self.schema {
+            Some(schema) if version != schema.version => {
+                codec.deserialize(&schema.upgrade(&codec, version, payload)?)
+            }
+            _ => codec.deserialize(payload),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+use crate::compression::{Compressed, Compression};
+use crate::errors::KvStoreError;
+use crate::schema::{CfSerializer, Schema};
+
+pub trait ByteSerializer {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError>;
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError>;
+
+    /// Whether no encoded value can start with 0xC1, as with MessagePack and
+    /// JSON. Only then can headers using that byte be told apart from values
+    /// written without one.
+    fn reserves_marker_byte(&self) -> bool {
+        false
+    }
+}
+
+/// Writes structs as maps keyed by field name, so stored values can be read
+/// without their Rust type. Earlier versions wrote positional arrays; those
+/// still deserialize into their type, but can't be read field by field
+/// until they are written again, e.g. with `rewrite_cf`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackSerializer;
+
+impl ByteSerializer for MessagePackSerializer {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
+        rmp_serde::to_vec_named(value).map_err(Into::into)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
+        rmp_serde::from_slice(bytes).map_err(Into::into)
+    }
+
+    fn reserves_marker_byte(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonSerializer;
+
+#[cfg(feature = "json")]
+impl ByteSerializer for JsonSerializer {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
+        serde_json::to_vec(value).map_err(|e| KvStoreError::SerializationError(e.to_string()))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
+        serde_json::from_slice(bytes).map_err(|e| KvStoreError::DeserializationError(e.to_string()))
+    }
+
+    fn reserves_marker_byte(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborSerializer;
+
+#[cfg(feature = "cbor")]
+impl ByteSerializer for CborSerializer {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes)
+            .map_err(|e| KvStoreError::SerializationError(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
+        ciborium::from_reader(bytes).map_err(|e| KvStoreError::DeserializationError(e.to_string()))
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeSerializer;
+
+#[cfg(feature = "bincode")]
+impl ByteSerializer for BincodeSerializer {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
+        bincode::serialize(value).map_err(|e| KvStoreError::SerializationError(e.to_string()))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
+        bincode::deserialize(bytes).map_err(|e| KvStoreError::DeserializationError(e.to_string()))
+    }
+}
+
+/// Value encoding used by a `RocksDB` instance, either as its default or as a
+/// per column family override.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    MessagePack,
+    #[cfg(feature = "json")]
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+impl Format {
+    /// Stable identifier recorded in value envelopes.
+    pub fn id(&self) -> u8 {
+        match self {
+            Format::MessagePack => 0,
+            #[cfg(feature = "json")]
+            Format::Json => 1,
+            #[cfg(feature = "cbor")]
+            Format::Cbor => 2,
+            #[cfg(feature = "bincode")]
+            Format::Bincode => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Format> {
+        match id {
+            0 => Some(Format::MessagePack),
+            #[cfg(feature = "json")]
+            1 => Some(Format::Json),
+            #[cfg(feature = "cbor")]
+            2 => Some(Format::Cbor),
+            #[cfg(feature = "bincode")]
+            3 => Some(Format::Bincode),
+            _ => None,
+        }
+    }
+}
+
+impl ByteSerializer for Format {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
+        match self {
+            Format::MessagePack => MessagePackSerializer.serialize(value),
+            #[cfg(feature = "json")]
+            Format::Json => JsonSerializer.serialize(value),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborSerializer.serialize(value),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => BincodeSerializer.serialize(value),
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
+        match self {
+            Format::MessagePack => MessagePackSerializer.deserialize(bytes),
+            #[cfg(feature = "json")]
+            Format::Json => JsonSerializer.deserialize(bytes),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborSerializer.deserialize(bytes),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => BincodeSerializer.deserialize(bytes),
+        }
+    }
+
+    fn reserves_marker_byte(&self) -> bool {
+        match self {
+            Format::MessagePack => true,
+            #[cfg(feature = "json")]
+            Format::Json => true,
+            #[cfg(feature = "cbor")]
+            Format::Cbor => false,
+            #[cfg(feature = "bincode")]
+            Format::Bincode => false,
+        }
+    }
+}
+
+/// Serializer resolved for a column family: its format, optionally wrapped in
+/// compression.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Codec {
+    Plain(Format),
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    Compressed(Compressed<Format>),
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Plain(Format::default())
+    }
+}
+
+impl Codec {
+    pub(crate) fn format(&self) -> Format {
+        match self {
+            Codec::Plain(format) => *format,
+            #[cfg(any(feature = "zstd", feature = "lz4"))]
+            Codec::Compressed(compressed) => *compressed.inner(),
+        }
+    }
+
+    pub(crate) fn with_format(self, format: Format) -> Codec {
+        match self {
+            Codec::Plain(_) => Codec::Plain(format),
+            #[cfg(any(feature = "zstd", feature = "lz4"))]
+            Codec::Compressed(compressed) => Codec::Compressed(
+                Compressed::new(format, compressed.compression())
+                    .with_threshold(compressed.threshold()),
+            ),
+        }
+    }
+}
+
+impl ByteSerializer for Codec {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, KvStoreError> {
+        match self {
+            Codec::Plain(format) => format.serialize(value),
+            #[cfg(any(feature = "zstd", feature = "lz4"))]
+            Codec::Compressed(compressed) => compressed.serialize(value),
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, KvStoreError> {
+        match self {
+            Codec::Plain(format) => format.deserialize(bytes),
+            #[cfg(any(feature = "zstd", feature = "lz4"))]
+            Codec::Compressed(compressed) => compressed.deserialize(bytes),
+        }
+    }
+
+    fn reserves_marker_byte(&self) -> bool {
+        match self {
+            Codec::Plain(format) => format.reserves_marker_byte(),
+            #[cfg(any(feature = "zstd", feature = "lz4"))]
+            Codec::Compressed(_) => false,
+        }
+    }
+}
+
+/// Serializer settings shared by every handle to a store: the default codec,
+/// per column family overrides and registered schemas.
+#[derive(Clone, Default)]
+pub(crate) struct SerializerRegistry {
+    default: Codec,
+    cf_codecs: Arc<RwLock<HashMap<String, Codec>>>,
+    schemas: Arc<RwLock<HashMap<String, Arc<Schema>>>>,
+}
+
+impl SerializerRegistry {
+    pub(crate) fn with_format(mut self, format: Format) -> Self {
+        self.default = self.default.with_format(format);
+        self
+    }
+
+    pub(crate) fn codec(&self, cf: &str) -> Codec {
+        self.cf_codecs
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cf)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    pub(crate) fn set_format(&self, cf: &str, format: Format) {
+        let mut cf_codecs = self
+            .cf_codecs
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let codec = cf_codecs.get(cf).copied().unwrap_or(self.default);
+        cf_codecs.insert(cf.to_string(), codec.with_format(format));
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    pub(crate) fn set_compression(&self, cf: &str, compression: Compression, threshold: usize) {
+        let mut cf_codecs = self
+            .cf_codecs
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let format = cf_codecs.get(cf).copied().unwrap_or(self.default).format();
+        let compressed = Compressed::new(format, compression).with_threshold(threshold);
+        cf_codecs.insert(cf.to_string(), Codec::Compressed(compressed));
+    }
+
+    pub(crate) fn register_schema(&self, cf: &str, schema: Schema) {
+        self.schemas
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(cf.to_string(), Arc::new(schema));
+    }
+
+    pub(crate) fn for_cf(&self, cf: &str) -> CfSerializer {
+        let schema = self
+            .schemas
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(cf)
+            .cloned();
+        CfSerializer::new(self.codec(cf), schema)
+    }
+}
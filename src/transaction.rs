@@ -0,0 +1,230 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rocksdb::{
+    BoundColumnFamily, ErrorKind, MultiThreaded, OptimisticTransactionDB, Options, TransactionDB,
+    TransactionDBOptions, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::errors::KvStoreError;
+use crate::schema::Schema;
+use crate::serializer::{ByteSerializer, Format, SerializerRegistry};
+
+enum Backend {
+    Optimistic(OptimisticTransactionDB<MultiThreaded>),
+    Pessimistic(TransactionDB<MultiThreaded>),
+}
+
+enum Txn<'db> {
+    Optimistic(rocksdb::Transaction<'db, OptimisticTransactionDB<MultiThreaded>>),
+    Pessimistic(rocksdb::Transaction<'db, TransactionDB<MultiThreaded>>),
+}
+
+// Both transaction flavours expose the same methods, just on different types
+macro_rules! with_txn {
+    ($txn:expr, $t:ident => $body:expr) => {
+        match $txn {
+            Txn::Optimistic($t) => $body,
+            Txn::Pessimistic($t) => $body,
+        }
+    };
+}
+
+/// Conflicts, lock timeouts and "try again" results are all resolved by
+/// retrying the transaction, so they share one error variant.
+fn txn_error(err: rocksdb::Error) -> KvStoreError {
+    match err.kind() {
+        ErrorKind::Busy | ErrorKind::TryAgain | ErrorKind::TimedOut => {
+            KvStoreError::TransactionConflict(err.to_string())
+        }
+        _ => KvStoreError::DbError(err),
+    }
+}
+
+/// A database opened through `OptimisticTransactionDB` or `TransactionDB`
+/// that hands out typed transactions.
+#[derive(Clone)]
+pub struct TransactionalRocksDB {
+    db: Arc<Backend>,
+    serializers: SerializerRegistry,
+}
+
+impl TransactionalRocksDB {
+    /// Opens the database with optimistic concurrency control: conflicts are
+    /// detected when the transaction commits.
+    pub fn open_optimistic<P, I, N>(opts: &Options, path: P, cfs: I) -> Result<Self, KvStoreError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>,
+    {
+        let cf_names: Vec<String> = cfs.into_iter().map(|n| n.as_ref().to_string()).collect();
+        let db = OptimisticTransactionDB::open_cf(opts, path, cf_names)?;
+        Ok(Self::new(Backend::Optimistic(db)))
+    }
+
+    /// Opens the database with pessimistic concurrency control: keys are locked
+    /// as they are written or read with `get_for_update_cf`.
+    pub fn open_pessimistic<P, I, N>(
+        opts: &Options,
+        txn_db_opts: &TransactionDBOptions,
+        path: P,
+        cfs: I,
+    ) -> Result<Self, KvStoreError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>,
+    {
+        let cf_names: Vec<String> = cfs.into_iter().map(|n| n.as_ref().to_string()).collect();
+        let db = TransactionDB::open_cf(opts, txn_db_opts, path, cf_names)?;
+        Ok(Self::new(Backend::Pessimistic(db)))
+    }
+
+    fn new(backend: Backend) -> Self {
+        TransactionalRocksDB {
+            db: Arc::new(backend),
+            serializers: SerializerRegistry::default(),
+        }
+    }
+
+    pub fn with_serializer(mut self, format: Format) -> Self {
+        self.serializers = self.serializers.with_format(format);
+        self
+    }
+
+    pub fn set_cf_serializer(&self, cf: &str, format: Format) {
+        self.serializers.set_format(cf, format);
+    }
+
+    pub fn register_schema(&self, cf: &str, schema: Schema) {
+        self.serializers.register_schema(cf, schema);
+    }
+
+    pub fn cf_handle(&self, cf: &str) -> Result<Arc<BoundColumnFamily<'_>>, KvStoreError> {
+        let handle = match self.db.as_ref() {
+            Backend::Optimistic(db) => db.cf_handle(cf),
+            Backend::Pessimistic(db) => db.cf_handle(cf),
+        };
+        handle.ok_or_else(|| KvStoreError::InvalidColumnFamily(cf.to_string()))
+    }
+
+    pub fn create_cf(&self, name: &str) -> Result<(), KvStoreError> {
+        if self.cf_handle(name).is_ok() {
+            return Ok(());
+        }
+        match self.db.as_ref() {
+            Backend::Optimistic(db) => db.create_cf(name, &Options::default())?,
+            Backend::Pessimistic(db) => db.create_cf(name, &Options::default())?,
+        }
+        Ok(())
+    }
+
+    /// Starts a new transaction. Dropping it without calling `commit` rolls
+    /// back every write made through it.
+    pub fn transaction(&self) -> Transaction<'_> {
+        let txn = match self.db.as_ref() {
+            Backend::Optimistic(db) => Txn::Optimistic(db.transaction()),
+            Backend::Pessimistic(db) => Txn::Pessimistic(db.transaction()),
+        };
+        Transaction { txn, store: self }
+    }
+
+    /// Runs `f` inside a fresh transaction and commits it, starting over up to
+    /// `max_retries` times when the transaction conflicts with another writer.
+    pub fn run_transaction<T, F>(&self, max_retries: usize, mut f: F) -> Result<T, KvStoreError>
+    where
+        F: FnMut(&Transaction<'_>) -> Result<T, KvStoreError>,
+    {
+        let mut attempt = 0;
+        loop {
+            let txn = self.transaction();
+            let result = f(&txn).and_then(|value| txn.commit().map(|_| value));
+
+            match result {
+                Err(KvStoreError::TransactionConflict(reason)) if attempt < max_retries => {
+                    attempt += 1;
+                    log::debug!(
+                        "Transaction conflict, retrying ({}/{}): {}",
+                        attempt,
+                        max_retries,
+                        reason
+                    );
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+pub struct Transaction<'db> {
+    txn: Txn<'db>,
+    store: &'db TransactionalRocksDB,
+}
+
+impl Transaction<'_> {
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<T, KvStoreError> {
+        self.get_cf(DEFAULT_COLUMN_FAMILY_NAME, key)
+    }
+
+    pub fn insert<T: Serialize>(&self, key: &str, value: &T) -> Result<(), KvStoreError> {
+        self.insert_cf(DEFAULT_COLUMN_FAMILY_NAME, key, value)
+    }
+
+    pub fn get_for_update<T: DeserializeOwned>(&self, key: &str) -> Result<T, KvStoreError> {
+        self.get_for_update_cf(DEFAULT_COLUMN_FAMILY_NAME, key)
+    }
+
+    pub fn get_cf<T: DeserializeOwned>(&self, cf: &str, key: &str) -> Result<T, KvStoreError> {
+        let cf_handle = self.store.cf_handle(cf)?;
+        let value = with_txn!(&self.txn, t => t.get_cf(&cf_handle, key.as_bytes()))
+            .map_err(txn_error)?
+            .ok_or_else(|| KvStoreError::KeyNotFound(key.to_string()))?;
+        self.store.serializers.for_cf(cf).deserialize(&value)
+    }
+
+    /// Reads a key and registers it with the transaction so that a concurrent
+    /// write to it makes this transaction fail with `TransactionConflict`.
+    pub fn get_for_update_cf<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        key: &str,
+    ) -> Result<T, KvStoreError> {
+        let cf_handle = self.store.cf_handle(cf)?;
+        let value =
+            with_txn!(&self.txn, t => t.get_for_update_cf(&cf_handle, key.as_bytes(), true))
+                .map_err(txn_error)?
+                .ok_or_else(|| KvStoreError::KeyNotFound(key.to_string()))?;
+        self.store.serializers.for_cf(cf).deserialize(&value)
+    }
+
+    pub fn insert_cf<T: Serialize>(
+        &self,
+        cf: &str,
+        key: &str,
+        value: &T,
+    ) -> Result<(), KvStoreError> {
+        let cf_handle = self.store.cf_handle(cf)?;
+        let serialized = self.store.serializers.for_cf(cf).serialize(value)?;
+        with_txn!(&self.txn, t => t.put_cf(&cf_handle, key.as_bytes(), serialized))
+            .map_err(txn_error)
+    }
+
+    pub fn delete_cf(&self, cf: &str, key: &str) -> Result<(), KvStoreError> {
+        let cf_handle = self.store.cf_handle(cf)?;
+
+        let _ = with_txn!(&self.txn, t => t.get_cf(&cf_handle, key.as_bytes()))
+            .map_err(txn_error)?
+            .ok_or(KvStoreError::KeyNotFound(key.to_string()))?;
+        with_txn!(&self.txn, t => t.delete_cf(&cf_handle, key.as_bytes())).map_err(txn_error)
+    }
+
+    pub fn commit(self) -> Result<(), KvStoreError> {
+        with_txn!(self.txn, t => t.commit()).map_err(txn_error)
+    }
+
+    pub fn rollback(&self) -> Result<(), KvStoreError> {
+        with_txn!(&self.txn, t => t.rollback()).map_err(txn_error)
+    }
+}
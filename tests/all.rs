@@ -1,7 +1,13 @@
 #[cfg(test)]
 mod tests {
-    use rocksdb_client::{KVStore, KvStoreError, Options, RocksDB};
+    use rocksdb_client::{
+        field, AbortReason, Aggregation, BackupManifest, BackupStore, CancellationToken, Direction,
+        ExportManifest, KVStore, KeyFilter, KeyValuePair, KvStoreError, Options, Page, PageToken,
+        Predicate, PrefixExtractor, QueryContext, QueryOptions, QueryPlan, RestoreMode,
+        RestoreOptions, RocksDB, Schema, SortOrder, TransactionalRocksDB,
+    };
     use serde::{Deserialize, Serialize};
+    use std::ops::Bound;
     use tempfile::TempDir;
 
     #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -97,6 +103,27 @@ mod tests {
         assert_eq!(user, retrieved_user);
     }
 
+    #[test]
+    fn test_messagepack_field_names() {
+        let (_temp_dir, db) = create_temp_db();
+        let user = TestUser {
+            id: 1,
+            name: "Alice".to_string(),
+        };
+
+        // Structs are written as maps keyed by field name
+        db.insert("user:1", &user).unwrap();
+        assert_eq!(
+            db.find("user:1").unwrap(),
+            Some(rmp_serde::to_vec_named(&user).unwrap())
+        );
+
+        // Values written as positional arrays still read back
+        db.save("user:2", &rmp_serde::to_vec(&user).unwrap())
+            .unwrap();
+        assert_eq!(db.get::<TestUser>("user:2").unwrap(), user);
+    }
+
     #[test]
     fn test_key_not_found() {
         let (_temp_dir, db) = create_temp_db();
@@ -129,4 +156,1659 @@ mod tests {
             assert_eq!(db.find(&key).unwrap(), Some(expected_value.into_bytes()));
         }
     }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_cf_serializer_override() {
+        use rocksdb_client::Format;
+
+        let (_temp_dir, db) = create_temp_db();
+        let db = db.with_serializer(Format::MessagePack);
+        db.create_cf("users").unwrap();
+        db.set_cf_serializer("default", Format::Json);
+        assert_eq!(db.cf_serializer("default"), Format::Json);
+        assert_eq!(db.cf_serializer("users"), Format::MessagePack);
+        assert_eq!(db.cf_serializer("unknown"), Format::MessagePack);
+
+        let user = TestUser {
+            id: 7,
+            name: "Grace".to_string(),
+        };
+        db.insert("user:7", &user).unwrap();
+        db.insert_cf("users", "user:7", &user).unwrap();
+
+        // Only the overridden column family stores JSON
+        let raw = db.find("user:7").unwrap().unwrap();
+        assert_eq!(raw, serde_json::to_vec(&user).unwrap());
+        assert_eq!(db.get::<TestUser>("user:7").unwrap(), user);
+        let retrieved: TestUser = db.get_cf("users", "user:7").unwrap();
+        assert_eq!(user, retrieved);
+
+        // The other one still holds MessagePack, which JSON cannot decode
+        db.set_cf_serializer("users", Format::Json);
+        assert!(db.get_cf::<TestUser>("users", "user:7").is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_serializer_stores_plain_json() {
+        use rocksdb_client::Format;
+
+        let (_temp_dir, db) = create_temp_db();
+        let db = db.with_serializer(Format::Json);
+        let user = TestUser {
+            id: 3,
+            name: "Ada".to_string(),
+        };
+        db.insert("user:3", &user).unwrap();
+
+        let raw = db.find("user:3").unwrap().unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&raw).unwrap();
+        assert_eq!(json["name"], "Ada");
+        assert_eq!(db.get::<TestUser>("user:3").unwrap(), user);
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    #[test]
+    fn test_compressed_cf_roundtrip() {
+        use rocksdb_client::{ByteSerializer, Compressed, Compression, MessagePackSerializer};
+
+        #[cfg(feature = "zstd")]
+        let compression = Compression::Zstd { level: 3 };
+        #[cfg(not(feature = "zstd"))]
+        let compression = Compression::Lz4;
+
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("blobs").unwrap();
+        db.set_cf_compression("blobs", compression, 64);
+
+        let small = vec![1u8; 8];
+        let large = vec![7u8; 64 * 1024];
+        db.insert_cf("blobs", "small", &small).unwrap();
+        db.insert_cf("blobs", "large", &large).unwrap();
+
+        assert_eq!(db.get_cf::<Vec<u8>>("blobs", "small").unwrap(), small);
+        assert_eq!(db.get_cf::<Vec<u8>>("blobs", "large").unwrap(), large);
+
+        let serializer = Compressed::new(MessagePackSerializer, compression).with_threshold(64);
+        assert!(serializer.serialize(&large).unwrap().len() < large.len());
+
+        // What is stored carries the header, and is only compressed past the threshold
+        #[cfg(feature = "zstd")]
+        let tag = 0x01;
+        #[cfg(not(feature = "zstd"))]
+        let tag = 0x02;
+        db.set_cf_compression("default", compression, 64);
+        db.insert_cf("default", "small", &small).unwrap();
+        db.insert_cf("default", "large", &large).unwrap();
+        let stored_small = db.find("small").unwrap().unwrap();
+        assert_eq!(stored_small[..2], [0xC1, 0x00]);
+        assert_eq!(
+            stored_small[2..],
+            MessagePackSerializer.serialize(&small).unwrap()[..]
+        );
+        let stored_large = db.find("large").unwrap().unwrap();
+        assert_eq!(stored_large[..2], [0xC1, tag]);
+        assert!(stored_large.len() < MessagePackSerializer.serialize(&large).unwrap().len());
+        assert_eq!(db.get::<Vec<u8>>("large").unwrap(), large);
+    }
+
+    #[cfg(all(feature = "bincode", any(feature = "zstd", feature = "lz4")))]
+    #[test]
+    fn test_compression_header_needs_reserved_marker() {
+        use rocksdb_client::{
+            BincodeSerializer, ByteSerializer, Compressed, Compression, MessagePackSerializer,
+        };
+
+        #[cfg(feature = "zstd")]
+        let compression = Compression::Zstd { level: 3 };
+        #[cfg(not(feature = "zstd"))]
+        let compression = Compression::Lz4;
+
+        // Bincode values may start with the header's marker byte, so a value
+        // written without a header is refused rather than guessed at
+        let value: (u32, u32) = (0xC1, 7);
+        let bincode = Compressed::new(BincodeSerializer, compression);
+        let tagged = bincode.serialize(&value).unwrap();
+        assert_eq!(bincode.deserialize::<(u32, u32)>(&tagged).unwrap(), value);
+        let untagged = BincodeSerializer.serialize(&(7u32, 7u32)).unwrap();
+        assert!(bincode.deserialize::<(u32, u32)>(&untagged).is_err());
+
+        // MessagePack never starts with 0xC1, so untagged values still read
+        let msgpack = Compressed::new(MessagePackSerializer, compression);
+        let untagged = MessagePackSerializer.serialize(&value).unwrap();
+        assert_eq!(msgpack.deserialize::<(u32, u32)>(&untagged).unwrap(), value);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_values_resembling_envelopes() {
+        use rocksdb_client::Format;
+
+        #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+        struct Reading {
+            sensor: u32,
+            label: String,
+        }
+
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("readings").unwrap();
+        db.set_cf_serializer("readings", Format::Bincode);
+
+        // Bincode writes these sensors as C1 EE 00 00 and C1 EE 03 00, the
+        // envelope marker followed by a valid format id
+        let readings = [
+            Reading {
+                sensor: 0x0000_EEC1,
+                label: "msgpack id".to_string(),
+            },
+            Reading {
+                sensor: 0x0003_EEC1,
+                label: "bincode id".to_string(),
+            },
+        ];
+        for (i, reading) in readings.iter().enumerate() {
+            db.insert_cf("readings", &format!("r{i}"), reading).unwrap();
+        }
+        for (i, reading) in readings.iter().enumerate() {
+            let stored: Reading = db.get_cf("readings", &format!("r{i}")).unwrap();
+            assert_eq!(&stored, reading);
+        }
+
+        // Values written without a schema are read as version 0 once one is
+        // registered
+        db.register_schema(
+            "readings",
+            Schema::new(1).migration(0, |reading: Reading| Reading {
+                label: reading.label.to_uppercase(),
+                ..reading
+            }),
+        );
+        let upgraded: Reading = db.get_cf("readings", "r1").unwrap();
+        assert_eq!(upgraded.sensor, 0x0003_EEC1);
+        assert_eq!(upgraded.label, "BINCODE ID");
+        db.insert_cf("readings", "r2", &readings[0]).unwrap();
+        assert_eq!(db.get_cf::<Reading>("readings", "r2").unwrap(), readings[0]);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_schema_envelopes_json_values() {
+        use rocksdb_client::Format;
+
+        let (_temp_dir, db) = create_temp_db();
+        db.set_cf_serializer("default", Format::Json);
+        db.insert_cf("default", "plain", &room(1, "Team")).unwrap();
+        let plain = db.find("plain").unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Room>(&plain).unwrap(),
+            room(1, "Team")
+        );
+
+        db.register_schema("default", Schema::new(1));
+        db.insert_cf("default", "enveloped", &room(1, "Team"))
+            .unwrap();
+        let enveloped = db.find("enveloped").unwrap().unwrap();
+        assert_eq!(enveloped[..3], [0xC1, 0xEE, Format::Json.id()]);
+        assert_eq!(enveloped[3..7], 1u32.to_le_bytes());
+        assert_eq!(
+            serde_json::from_slice::<Room>(&enveloped[7..]).unwrap(),
+            room(1, "Team")
+        );
+    }
+
+    #[test]
+    fn test_schema_migrations() {
+        #[derive(Serialize, Deserialize)]
+        struct RoomV0 {
+            id: u32,
+            name: String,
+        }
+
+        #[derive(Debug, Serialize, Deserialize, PartialEq)]
+        struct Room {
+            id: u32,
+            name: String,
+            capacity: u32,
+        }
+
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        let old = RoomV0 {
+            id: 1,
+            name: "Lobby".to_string(),
+        };
+        db.insert_cf("rooms", "room:1", &old).unwrap();
+        db.insert_cf("rooms", "room:2", &old).unwrap();
+
+        db.register_schema(
+            "rooms",
+            Schema::new(1).migration(0, |old: RoomV0| Room {
+                id: old.id,
+                name: old.name,
+                capacity: 8,
+            }),
+        );
+
+        let expected = Room {
+            id: 1,
+            name: "Lobby".to_string(),
+            capacity: 8,
+        };
+        assert_eq!(db.get_cf::<Room>("rooms", "room:1").unwrap(), expected);
+
+        assert_eq!(db.migrate_cf("rooms").unwrap(), 2);
+        assert_eq!(db.migrate_cf("rooms").unwrap(), 0);
+        assert_eq!(db.get_cf::<Room>("rooms", "room:2").unwrap(), expected);
+
+        db.insert_cf("rooms", "room:3", &expected).unwrap();
+        assert_eq!(db.get_cf::<Room>("rooms", "room:3").unwrap(), expected);
+
+        db.register_schema("rooms", Schema::new(2));
+        match db.get_cf::<Room>("rooms", "room:3") {
+            Err(KvStoreError::MigrationError(_)) => (),
+            other => panic!("Expected MigrationError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_optimistic_transaction_conflict_and_retry() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let db =
+            TransactionalRocksDB::open_optimistic(&opts, temp_dir.path(), ["default", "counters"])
+                .unwrap();
+
+        let setup = db.transaction();
+        setup.insert_cf("counters", "hits", &0u64).unwrap();
+        setup.commit().unwrap();
+
+        let first = db.transaction();
+        let second = db.transaction();
+        let a: u64 = first.get_for_update_cf("counters", "hits").unwrap();
+        let b: u64 = second.get_for_update_cf("counters", "hits").unwrap();
+        first.insert_cf("counters", "hits", &(a + 1)).unwrap();
+        second.insert_cf("counters", "hits", &(b + 1)).unwrap();
+        first.commit().unwrap();
+        match second.commit() {
+            Err(KvStoreError::TransactionConflict(_)) => (),
+            other => panic!("Expected TransactionConflict, got {:?}", other),
+        }
+
+        let mut attempts = 0;
+        let hits = db
+            .run_transaction(3, |txn| {
+                attempts += 1;
+                let hits: u64 = txn.get_for_update_cf("counters", "hits")?;
+                if attempts == 1 {
+                    let other = db.transaction();
+                    other.insert_cf("counters", "hits", &100u64)?;
+                    other.commit()?;
+                }
+                txn.insert_cf("counters", "hits", &(hits + 1))?;
+                Ok(hits + 1)
+            })
+            .unwrap();
+        assert_eq!(attempts, 2);
+        assert_eq!(hits, 101);
+        assert_eq!(
+            db.transaction().get_cf::<u64>("counters", "hits").unwrap(),
+            101
+        );
+    }
+
+    #[test]
+    fn test_mixed_batch() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("users").unwrap();
+        db.create_cf("counters").unwrap();
+        for i in 0..5 {
+            db.insert_cf("counters", &format!("c{}", i), &(i as u64))
+                .unwrap();
+        }
+        db.insert_cf(
+            "users",
+            "user:old",
+            &TestUser {
+                id: 0,
+                name: "Old".to_string(),
+            },
+        )
+        .unwrap();
+
+        let user = TestUser {
+            id: 1,
+            name: "Linus".to_string(),
+        };
+        let mut batch = db.batch();
+        batch
+            .put_cf("users", "user:1", &user)
+            .unwrap()
+            .put_cf("counters", "total", &42u64)
+            .unwrap()
+            .delete_cf("users", "user:old")
+            .unwrap()
+            .delete_range_cf("counters", "c1", "c4")
+            .unwrap();
+        assert_eq!(batch.len(), 4);
+        assert!(db.get_cf::<TestUser>("users", "user:1").is_err());
+        batch.write().unwrap();
+
+        assert_eq!(db.get_cf::<TestUser>("users", "user:1").unwrap(), user);
+        assert_eq!(db.get_cf::<u64>("counters", "total").unwrap(), 42);
+        assert!(db.get_cf::<TestUser>("users", "user:old").is_err());
+        assert_eq!(db.get_cf::<u64>("counters", "c0").unwrap(), 0);
+        assert!(db.get_cf::<u64>("counters", "c2").is_err());
+        assert_eq!(db.get_cf::<u64>("counters", "c4").unwrap(), 4);
+
+        assert!(db.batch().put_cf("missing", "k", &1u8).is_err());
+    }
+
+    #[test]
+    fn test_key_range_scans() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("numbers").unwrap();
+        for i in 0..10u32 {
+            db.insert_cf("numbers", &format!("k{:02}", i), &i).unwrap();
+        }
+
+        let half_open: Vec<u32> = db
+            .get_range_cf("numbers", "k02".."k05", 100, Direction::Forward)
+            .unwrap();
+        assert_eq!(half_open, vec![2, 3, 4]);
+
+        let inclusive: Vec<u32> = db
+            .get_range_cf("numbers", "k02"..="k05", 100, Direction::Forward)
+            .unwrap();
+        assert_eq!(inclusive, vec![2, 3, 4, 5]);
+
+        let reversed = db
+            .get_range_cf_with_keys::<u32, _>("numbers", "k02"..="k05", 2, Direction::Reverse)
+            .unwrap();
+        let keys: Vec<&str> = reversed.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["k05", "k04"]);
+
+        let excluded_start: Vec<u32> = db
+            .get_range_cf(
+                "numbers",
+                (Bound::Excluded("k07"), Bound::Unbounded),
+                100,
+                Direction::Forward,
+            )
+            .unwrap();
+        assert_eq!(excluded_start, vec![8, 9]);
+
+        let tail: Vec<u32> = db
+            .get_range_cf("numbers", ..="k01", 100, Direction::Reverse)
+            .unwrap();
+        assert_eq!(tail, vec![1, 0]);
+
+        let everything: Vec<u32> = db
+            .get_range_cf("numbers", .., 3, Direction::Forward)
+            .unwrap();
+        assert_eq!(everything, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_paging_with_continuation_tokens() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("numbers").unwrap();
+        for i in 0..5u32 {
+            db.insert_cf("numbers", &format!("k{:02}", i), &i).unwrap();
+        }
+
+        let mut seen = Vec::new();
+        let mut token: Option<PageToken> = None;
+        loop {
+            let page: Page<u32> = db
+                .page_cf("numbers", .., 2, Direction::Forward, token.as_ref())
+                .unwrap();
+            seen.extend(page.items);
+            match page.next {
+                // Tokens survive a round trip through their string form
+                Some(next) => token = Some(next.to_string().parse().unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec![0, 1, 2, 3, 4]);
+
+        let first = db
+            .page_cf_with_keys::<u32, _>("numbers", "k01".., 2, Direction::Reverse, None)
+            .unwrap();
+        let keys: Vec<&str> = first.items.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["k04", "k03"]);
+
+        let second = db
+            .page_cf_with_keys::<u32, _>(
+                "numbers",
+                "k01"..,
+                2,
+                Direction::Reverse,
+                first.next.as_ref(),
+            )
+            .unwrap();
+        let keys: Vec<&str> = second.items.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["k02", "k01"]);
+        assert!(second.next.is_none());
+
+        let mismatched =
+            db.page_cf::<u32, _>("numbers", .., 2, Direction::Forward, first.next.as_ref());
+        assert!(matches!(mismatched, Err(KvStoreError::InvalidQuery(_))));
+        assert!("zz".parse::<PageToken>().is_err());
+
+        // Tokens from outside the requested range never widen it
+        let early = db
+            .page_cf_with_keys::<u32, _>("numbers", .., 1, Direction::Forward, None)
+            .unwrap();
+        let resumed = db
+            .page_cf_with_keys::<u32, _>(
+                "numbers",
+                "k02".."k04",
+                5,
+                Direction::Forward,
+                early.next.as_ref(),
+            )
+            .unwrap();
+        let keys: Vec<&str> = resumed.items.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["k02", "k03"]);
+        let past_end = db
+            .page_cf_with_keys::<u32, _>(
+                "numbers",
+                ..="k01",
+                5,
+                Direction::Reverse,
+                first.next.as_ref(),
+            )
+            .unwrap();
+        let keys: Vec<&str> = past_end
+            .items
+            .iter()
+            .map(|pair| pair.key.as_str())
+            .collect();
+        assert_eq!(keys, vec!["k01", "k00"]);
+        let beyond = db
+            .page_cf_with_keys::<u32, _>("numbers", "k03".., 1, Direction::Forward, None)
+            .unwrap();
+        let late = db
+            .page_cf::<u32, _>(
+                "numbers",
+                ..="k01",
+                5,
+                Direction::Forward,
+                beyond.next.as_ref(),
+            )
+            .unwrap();
+        assert!(late.items.is_empty() && late.next.is_none());
+    }
+
+    #[test]
+    fn test_scan_prefix_with_extractor() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf_with_prefix(
+            "rooms",
+            PrefixExtractor::delimited::<b':', 2>().with_bloom_filter(10.0),
+        )
+        .unwrap();
+        for room in 1..=2u32 {
+            for player in 1..=3u32 {
+                let key = format!("room:{}:player:{}", room, player);
+                db.insert_cf("rooms", &key, &(room * 10 + player)).unwrap();
+            }
+        }
+        db.insert_cf("rooms", "room:10:player:1", &101u32).unwrap();
+
+        let room_one = db.scan_prefix_cf::<u32>("rooms", "room:1:").unwrap();
+        let keys: Vec<&str> = room_one.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["room:1:player:1", "room:1:player:2", "room:1:player:3"]
+        );
+
+        // Prefixes the extractor does not produce fall back to a total order scan
+        let all_rooms = db.scan_prefix_cf::<u32>("rooms", "room:").unwrap();
+        assert_eq!(all_rooms.len(), 7);
+        let player = db
+            .scan_prefix_cf::<u32>("rooms", "room:2:player:3")
+            .unwrap();
+        assert_eq!(player.len(), 1);
+        assert_eq!(player[0].value, 23);
+
+        db.create_cf_with_prefix("fixed", PrefixExtractor::fixed(4))
+            .unwrap();
+        db.insert_cf("fixed", "abcd1", &1u32).unwrap();
+        db.insert_cf("fixed", "abce1", &2u32).unwrap();
+        let values: Vec<u32> = db
+            .scan_prefix_cf::<u32>("fixed", "abcd")
+            .unwrap()
+            .into_iter()
+            .map(|pair| pair.value)
+            .collect();
+        assert_eq!(values, vec![1]);
+        assert!(db.scan_prefix_cf::<u32>("fixed", "zz").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prefix_extractors_survive_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let extractor = PrefixExtractor::delimited::<b':', 2>();
+        {
+            let db = RocksDB::open_with_existing_cfs(&opts, temp_dir.path()).unwrap();
+            db.create_cf_with_prefix("rooms", extractor).unwrap();
+            db.insert_cf("rooms", "room:1:player:1", &11u32).unwrap();
+            db.insert_cf("rooms", "room:2:player:1", &21u32).unwrap();
+            // Registering again is fine, a different extractor or a plain CF isn't
+            db.create_cf_with_prefix("rooms", extractor).unwrap();
+            assert!(matches!(
+                db.create_cf_with_prefix("rooms", PrefixExtractor::fixed(4)),
+                Err(KvStoreError::InvalidColumnFamily(_))
+            ));
+            db.create_cf("plain").unwrap();
+            assert!(matches!(
+                db.create_cf_with_prefix("plain", extractor),
+                Err(KvStoreError::InvalidColumnFamily(_))
+            ));
+        }
+
+        // The extractor has to be given again
+        assert!(matches!(
+            RocksDB::open_with_existing_cfs(&opts, temp_dir.path()),
+            Err(KvStoreError::InvalidColumnFamily(_))
+        ));
+        assert!(matches!(
+            RocksDB::open_cf_with_prefixes(
+                &opts,
+                temp_dir.path(),
+                ["default", "plain"],
+                &[("rooms", PrefixExtractor::fixed(4))],
+            ),
+            Err(KvStoreError::InvalidColumnFamily(_))
+        ));
+
+        let db = RocksDB::open_cf_with_prefixes(
+            &opts,
+            temp_dir.path(),
+            ["default", "plain"],
+            &[("rooms", extractor)],
+        )
+        .unwrap();
+        let values: Vec<u32> = db
+            .scan_prefix_cf::<u32>("rooms", "room:1:")
+            .unwrap()
+            .into_iter()
+            .map(|pair| pair.value)
+            .collect();
+        assert_eq!(values, vec![11]);
+
+        // Dropping the CF forgets its extractor
+        db.drop_cf("rooms").unwrap();
+        drop(db);
+        RocksDB::open_with_existing_cfs(&opts, temp_dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_typed_iter() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("numbers").unwrap();
+        for i in 0..10u32 {
+            db.insert_cf("numbers", &format!("k{:02}", i), &i).unwrap();
+        }
+        db.insert_cf("numbers", "z", &"not a number").unwrap();
+
+        let page: Vec<u32> = db
+            .iter_cf::<u32, _>("numbers", .., Direction::Forward)
+            .unwrap()
+            .skip(3)
+            .take(4)
+            .map(|item| item.unwrap().value)
+            .collect();
+        assert_eq!(page, vec![3, 4, 5, 6]);
+
+        let keys: Vec<String> = db
+            .iter_cf::<u32, _>("numbers", "k04"..="k06", Direction::Reverse)
+            .unwrap()
+            .map(|item| item.unwrap().key)
+            .collect();
+        assert_eq!(keys, vec!["k06", "k05", "k04"]);
+
+        // Values are only decoded when yielded, so the bad entry fails on its own
+        let mut iter = db
+            .iter_cf::<u32, _>("numbers", "k09".., Direction::Forward)
+            .unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().value, 9);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Room {
+        owner: u32,
+        style: String,
+    }
+
+    fn room(owner: u32, style: &str) -> Room {
+        Room {
+            owner,
+            style: style.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_secondary_indexes() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        db.insert_cf("rooms", "r1", &room(5, "modern")).unwrap();
+        db.insert_cf("rooms", "r2", &room(7, "rustic")).unwrap();
+
+        // Existing documents are indexed when the index is created
+        db.create_index::<Room>("rooms", "owner", "$.owner")
+            .unwrap();
+        db.create_index::<Room>("rooms", "style", "$.style")
+            .unwrap();
+        db.batch_insert_cf(
+            "rooms",
+            &[("r3", &room(5, "rustic")), ("r4", &room(9, "modern"))],
+        )
+        .unwrap();
+
+        let keys = |pairs: Vec<KeyValuePair<Room>>| -> Vec<String> {
+            pairs.into_iter().map(|pair| pair.key).collect()
+        };
+        let owned = db
+            .find_by_index_cf::<Room, _>("rooms", "owner", &5)
+            .unwrap();
+        assert_eq!(keys(owned), vec!["r1", "r3"]);
+        let range = db
+            .find_by_index_range_cf::<Room, u32, _>("rooms", "owner", 6..=9)
+            .unwrap();
+        assert_eq!(keys(range), vec!["r2", "r4"]);
+
+        // Updates move entries and deletes remove them
+        db.insert_cf("rooms", "r1", &room(7, "modern")).unwrap();
+        db.delete_cf("rooms", "r3").unwrap();
+        let owned = db
+            .find_by_index_cf::<Room, _>("rooms", "owner", &5)
+            .unwrap();
+        assert!(owned.is_empty());
+        let owned = db
+            .find_by_index_cf::<Room, _>("rooms", "owner", &7)
+            .unwrap();
+        assert_eq!(keys(owned), vec!["r1", "r2"]);
+
+        let mut batch = db.batch();
+        batch.put_cf("rooms", "r5", &room(1, "rustic")).unwrap();
+        batch.delete_cf("rooms", "r2").unwrap();
+        assert!(batch.delete_range_cf("rooms", "r0", "r9").is_err());
+        batch.write().unwrap();
+        let rustic = db
+            .find_by_index_cf::<Room, _>("rooms", "style", &"rustic")
+            .unwrap();
+        assert_eq!(rustic.len(), 1);
+        assert_eq!(rustic[0].key, "r5");
+        assert_eq!(rustic[0].value, room(1, "rustic"));
+
+        assert!(matches!(
+            db.find_by_index_cf::<Room, _>("rooms", "missing", &1),
+            Err(KvStoreError::InvalidQuery(_))
+        ));
+        db.drop_index("rooms", "style").unwrap();
+        assert!(db
+            .find_by_index_cf::<Room, _>("rooms", "style", &"rustic")
+            .is_err());
+    }
+
+    #[test]
+    fn test_indexes_survive_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        {
+            let db = RocksDB::open_with_existing_cfs(&opts, temp_dir.path()).unwrap();
+            db.create_cf("rooms").unwrap();
+            db.insert_cf("rooms", "r1", &room(5, "modern")).unwrap();
+            db.create_index::<Room>("rooms", "style", "$.style")
+                .unwrap();
+        }
+
+        // Writes made before create_index is called again still update the index
+        let db = RocksDB::open_with_existing_cfs(&opts, temp_dir.path()).unwrap();
+        db.insert_cf("rooms", "r2", &room(7, "modern")).unwrap();
+        db.insert_cf("rooms", "r1", &room(5, "rustic")).unwrap();
+        let keys = |index: &str, value: serde_json::Value| -> Vec<String> {
+            db.find_by_index_cf::<Room, _>("rooms", index, &value)
+                .unwrap()
+                .into_iter()
+                .map(|pair| pair.key)
+                .collect()
+        };
+        assert_eq!(keys("style", "modern".into()), vec!["r2"]);
+        assert_eq!(keys("style", "rustic".into()), vec!["r1"]);
+
+        // Migrated documents are reindexed
+        db.register_schema(
+            "rooms",
+            Schema::new(1).migration(0, |old: Room| room(old.owner, &old.style.to_uppercase())),
+        );
+        assert_eq!(db.migrate_cf("rooms").unwrap(), 2);
+        assert!(keys("style", "modern".into()).is_empty());
+        assert_eq!(keys("style", "MODERN".into()), vec!["r2"]);
+
+        // Defining the same index with another path rebuilds it
+        db.create_index::<Room>("rooms", "style", "$.owner")
+            .unwrap();
+        assert!(keys("style", "MODERN".into()).is_empty());
+        assert_eq!(keys("style", 7.into()), vec!["r2"]);
+    }
+
+    #[test]
+    fn test_unbuilt_indexes_are_not_queried() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        db.insert_cf("rooms", "r1", &room(1, "Team")).unwrap();
+        // Can't be decoded as a Room, so building an index over it fails
+        db.insert_cf("rooms", "r2", &serde_json::json!({ "style": 5 }))
+            .unwrap();
+        assert!(db
+            .create_index::<Room>("rooms", "style", "$.style")
+            .is_err());
+
+        let query = "$[?@.style == 'Team']";
+        assert_eq!(
+            db.explain_query("rooms", query).unwrap(),
+            QueryPlan::FullScan
+        );
+        assert!(matches!(
+            db.find_by_index_cf::<Room, _>("rooms", "style", &"Team"),
+            Err(KvStoreError::InvalidQuery(_))
+        ));
+
+        db.delete_cf("rooms", "r2").unwrap();
+        db.create_index::<Room>("rooms", "style", "$.style")
+            .unwrap();
+        assert!(matches!(
+            db.explain_query("rooms", query).unwrap(),
+            QueryPlan::IndexScan { .. }
+        ));
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Lobby {
+        style: String,
+        player_count: u32,
+        capacity: u32,
+    }
+
+    /// Creates "lobbies" holding `(key, style, player_count, capacity)` rows.
+    fn insert_lobbies(db: &RocksDB, lobbies: &[(&str, &str, u32, u32)]) {
+        db.create_cf("lobbies").unwrap();
+        for &(key, style, player_count, capacity) in lobbies {
+            let lobby = Lobby {
+                style: style.to_string(),
+                player_count,
+                capacity,
+            };
+            db.insert_cf("lobbies", key, &lobby).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_query_planner_uses_indexes() {
+        let (_temp_dir, db) = create_temp_db();
+        insert_lobbies(
+            &db,
+            &[
+                ("l1", "Team", 2, 4),
+                ("l2", "Team", 4, 4),
+                ("l3", "Solo", 1, 4),
+                ("l4", "Team", 0, 8),
+            ],
+        );
+
+        let query = "$[?@.style=='Team'&&@.player_count<@.capacity]";
+        assert_eq!(
+            db.explain_query("lobbies", query).unwrap(),
+            QueryPlan::FullScan
+        );
+        let scanned = db.query_cf_with_keys::<Lobby>("lobbies", query).unwrap();
+
+        db.create_index::<Lobby>("lobbies", "style", "$.style")
+            .unwrap();
+        db.create_index::<Lobby>("lobbies", "players", "$.player_count")
+            .unwrap();
+        match db.explain_query("lobbies", query).unwrap() {
+            QueryPlan::IndexScan {
+                index, residual, ..
+            } => {
+                assert_eq!(index, "style");
+                assert!(residual.is_some());
+            }
+            plan => panic!("expected an index scan, got {}", plan),
+        }
+
+        let indexed = db.query_cf_with_keys::<Lobby>("lobbies", query).unwrap();
+        let keys: Vec<&str> = indexed.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["l1", "l4"]);
+        let scanned_keys: Vec<&str> = scanned.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, scanned_keys);
+
+        // Range predicates use the index when no equality is available
+        let range = "$[?@.player_count >= 1 && @.player_count < 4]";
+        assert!(matches!(
+            db.explain_query("lobbies", range).unwrap(),
+            QueryPlan::IndexScan { ref index, residual: None, .. } if index == "players"
+        ));
+        let values: Vec<Lobby> = db.query_cf("lobbies", range).unwrap();
+        assert_eq!(values.len(), 2);
+
+        let unindexed = "$[?@.capacity == 8]";
+        assert_eq!(
+            db.explain_query("lobbies", unindexed).unwrap(),
+            QueryPlan::FullScan
+        );
+
+        // `$` is the whole column family, so root references skip the index
+        let rooted = "$[?@.style == 'Team' && @.capacity > $[0].capacity]";
+        assert_eq!(
+            db.explain_query("lobbies", rooted).unwrap(),
+            QueryPlan::FullScan
+        );
+        let bigger = db.query_cf_with_keys::<Lobby>("lobbies", rooted).unwrap();
+        let keys: Vec<&str> = bigger.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["l4"]);
+    }
+
+    #[test]
+    fn test_query_matches_documents_by_key() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("users").unwrap();
+        let same = TestUser {
+            id: 1,
+            name: "twin".to_string(),
+        };
+        db.insert_cf("users", "a", &same).unwrap();
+        db.insert_cf(
+            "users",
+            "b",
+            &TestUser {
+                id: 2,
+                name: "other".to_string(),
+            },
+        )
+        .unwrap();
+        db.insert_cf("users", "c", &same).unwrap();
+
+        let twins = db
+            .query_cf_with_keys::<TestUser>("users", "$[?@.name == 'twin']")
+            .unwrap();
+        let keys: Vec<&str> = twins.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "c"]);
+
+        // Non-filter queries select documents by position
+        let picked = db
+            .query_cf_with_keys::<TestUser>("users", "$[1,2]")
+            .unwrap();
+        let keys: Vec<&str> = picked.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["b", "c"]);
+
+        assert!(matches!(
+            db.query_cf::<TestUser>("users", "$[?@.name ==]"),
+            Err(KvStoreError::InvalidQuery(_))
+        ));
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct LobbySummary {
+        style: String,
+        capacity: u32,
+    }
+
+    #[test]
+    fn test_query_options() {
+        let (_temp_dir, db) = create_temp_db();
+        insert_lobbies(
+            &db,
+            &[
+                ("l1", "Team", 2, 4),
+                ("l2", "Solo", 1, 2),
+                ("l3", "Team", 3, 8),
+                ("l4", "Duo", 0, 6),
+            ],
+        );
+
+        let options = QueryOptions::new()
+            .order_by("capacity", SortOrder::Desc)
+            .offset(1)
+            .limit(2)
+            .select(["style", "capacity"]);
+        let summaries = db
+            .query_cf_with_options::<Lobby, LobbySummary>("lobbies", "$[?@.capacity > 2]", &options)
+            .unwrap();
+        let keys: Vec<&str> = summaries.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["l4", "l1"]);
+        assert_eq!(
+            summaries[0].value,
+            LobbySummary {
+                style: "Duo".to_string(),
+                capacity: 6
+            }
+        );
+
+        // Projections can also come back as plain JSON
+        let projected = db
+            .query_cf_with_options::<Lobby, serde_json::Value>(
+                "lobbies",
+                "$[?@.style == 'Team']",
+                &QueryOptions::new().limit(1).select(["player_count"]),
+            )
+            .unwrap();
+        assert_eq!(projected.len(), 1);
+        assert_eq!(projected[0].key, "l1");
+        assert_eq!(projected[0].value, serde_json::json!({ "player_count": 2 }));
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct StyleStats {
+        lobbies: u64,
+        players: u32,
+        largest: u32,
+    }
+
+    #[test]
+    fn test_aggregations() {
+        let (_temp_dir, db) = create_temp_db();
+        insert_lobbies(
+            &db,
+            &[
+                ("l1", "Team", 2, 4),
+                ("l2", "Solo", 1, 2),
+                ("l3", "Team", 3, 8),
+                ("l4", "Duo", 0, 6),
+            ],
+        );
+
+        let by_style = Aggregation::new()
+            .group_by("style")
+            .count("lobbies")
+            .sum("players", "player_count")
+            .max("largest", "capacity");
+        let stats = db.aggregate_cf::<StyleStats>("lobbies", &by_style).unwrap();
+        assert_eq!(stats.len(), 3);
+        assert_eq!(
+            stats["Team"],
+            StyleStats {
+                lobbies: 2,
+                players: 5,
+                largest: 8
+            }
+        );
+
+        let filtered = Aggregation::new()
+            .filter("$[?@.capacity > 2]")
+            .count("lobbies")
+            .avg("players", "player_count")
+            .min("smallest", "capacity");
+        let totals = db
+            .aggregate_cf::<serde_json::Value>("lobbies", &filtered)
+            .unwrap();
+        assert_eq!(
+            totals[""],
+            serde_json::json!({ "lobbies": 3, "players": 5.0 / 3.0, "smallest": 4 })
+        );
+
+        // An ungrouped aggregation always has a result
+        db.create_cf("empty").unwrap();
+        let empty = db
+            .aggregate_cf::<serde_json::Value>(
+                "empty",
+                &Aggregation::new().count("n").avg("a", "x"),
+            )
+            .unwrap();
+        assert_eq!(empty[""], serde_json::json!({ "n": 0, "a": null }));
+
+        // Structs written as positional arrays have no field names to group by
+        let lobby = Lobby {
+            style: "Team".to_string(),
+            player_count: 1,
+            capacity: 4,
+        };
+        db.save("l5", &rmp_serde::to_vec(&lobby).unwrap()).unwrap();
+        assert!(matches!(
+            db.aggregate_cf::<StyleStats>("default", &by_style),
+            Err(KvStoreError::DeserializationError(_))
+        ));
+
+        // Rewriting them through their type stores them as maps
+        assert_eq!(db.rewrite_cf::<Lobby>("default").unwrap(), 1);
+        assert_eq!(db.rewrite_cf::<Lobby>("default").unwrap(), 0);
+        let stats = db.aggregate_cf::<StyleStats>("default", &by_style).unwrap();
+        assert_eq!(
+            stats["Team"],
+            StyleStats {
+                lobbies: 1,
+                players: 1,
+                largest: 4
+            }
+        );
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_aggregations_reject_bincode() {
+        use rocksdb_client::Format;
+
+        let (_temp_dir, db) = create_temp_db();
+        db.set_cf_serializer("lobbies", Format::Bincode);
+        insert_lobbies(&db, &[("l1", "Team", 2, 4)]);
+        assert_eq!(db.get_cf::<Lobby>("lobbies", "l1").unwrap().capacity, 4);
+        assert!(matches!(
+            db.aggregate_cf::<serde_json::Value>("lobbies", &Aggregation::new().count("n")),
+            Err(KvStoreError::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn test_query_by_key_pattern() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        for (key, owner, style) in [
+            ("room:eu:1", 1, "Team"),
+            ("room:eu:2", 2, "Solo"),
+            ("room:eu:3", 3, "Team"),
+            ("room:us:1", 4, "Team"),
+            ("user:1", 5, "Team"),
+        ] {
+            db.insert_cf("rooms", key, &room(owner, style)).unwrap();
+        }
+
+        let keys_of = |filter: KeyFilter, query: &str| -> Vec<String> {
+            db.query_cf_by_keys::<Room>("rooms", &filter, query)
+                .unwrap()
+                .into_iter()
+                .map(|pair| pair.key)
+                .collect()
+        };
+        let team = "$[?@.style == 'Team']";
+        assert_eq!(
+            keys_of(KeyFilter::prefix("room:eu:"), team),
+            vec!["room:eu:1", "room:eu:3"]
+        );
+        assert_eq!(
+            keys_of(KeyFilter::glob("room:*:1"), team),
+            vec!["room:eu:1", "room:us:1"]
+        );
+        assert_eq!(
+            keys_of(KeyFilter::regex("^room:(eu|us):[13]$").unwrap(), team),
+            vec!["room:eu:1", "room:eu:3", "room:us:1"]
+        );
+        assert_eq!(
+            keys_of(
+                KeyFilter::range("room:eu:2".."room:us:1"),
+                "$[?@.owner > 0]"
+            ),
+            vec!["room:eu:2", "room:eu:3"]
+        );
+        assert!(matches!(
+            KeyFilter::regex("("),
+            Err(KvStoreError::InvalidQuery(_))
+        ));
+
+        // Index lookups are narrowed by the key filter too
+        db.create_index::<Room>("rooms", "by_style", "$.style")
+            .unwrap();
+        assert_eq!(
+            keys_of(KeyFilter::prefix("room:"), team),
+            vec!["room:eu:1", "room:eu:3", "room:us:1"]
+        );
+
+        // Values under other keys are never decoded
+        db.insert_cf("rooms", "meta", &"not a room").unwrap();
+        let options = QueryOptions::new()
+            .keys(KeyFilter::glob("room:??:*"))
+            .limit(2);
+        let rooms = db
+            .query_cf_with_options::<Room, Room>("rooms", team, &options)
+            .unwrap();
+        assert_eq!(rooms.len(), 2);
+    }
+
+    #[test]
+    fn test_parallel_query() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        // Small memtables and SST files leave several files to split on, even
+        // after compaction. Opening "rooms" here gives it these options too.
+        opts.set_write_buffer_size(64 * 1024);
+        opts.set_target_file_size_base(64 * 1024);
+        let db = RocksDB::open_cf(&opts, temp_dir.path(), ["default", "rooms"]).unwrap();
+        for i in 0..5000u32 {
+            let style = if i % 3 == 0 { "Team" } else { "Solo" };
+            db.insert_cf("rooms", &format!("room:{:05}", i), &room(i, style))
+                .unwrap();
+        }
+        db.flush_cf("rooms").unwrap();
+        let ranges = db.split_cf("rooms", 4).unwrap();
+        assert!(ranges.len() > 1 && ranges.len() <= 4);
+        assert!(matches!(
+            db.split_cf("rooms", 0),
+            Err(KvStoreError::InvalidQuery(_))
+        ));
+
+        let query = "$[?@.style == 'Team' && @.owner >= 100]";
+        let sequential = db.query_cf_with_keys::<Room>("rooms", query).unwrap();
+        let parallel = db.par_query_cf::<Room>("rooms", query, 4).unwrap();
+        assert_eq!(parallel.len(), 1633);
+        let keys = |pairs: &[KeyValuePair<Room>]| -> Vec<String> {
+            pairs.iter().map(|pair| pair.key.clone()).collect()
+        };
+        assert_eq!(keys(&parallel), keys(&sequential));
+        assert_eq!(parallel[0].value, room(102, "Team"));
+        // Zero threads runs the query on the calling thread
+        assert_eq!(
+            keys(&db.par_query_cf::<Room>("rooms", query, 0).unwrap()),
+            keys(&sequential)
+        );
+    }
+
+    #[test]
+    fn test_query_context_limits() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        for i in 0..10 {
+            let style = if i % 2 == 0 { "Team" } else { "Solo" };
+            db.insert_cf("rooms", &format!("room:{}", i), &room(i, style))
+                .unwrap();
+        }
+        let query = "$[?@.style == 'Team']";
+
+        let complete = db
+            .query_cf_with_context::<Room>(
+                "rooms",
+                query,
+                &QueryContext::new().max_scanned_rows(10),
+            )
+            .unwrap();
+        assert_eq!(complete.items.len(), 5);
+        assert_eq!(complete.aborted, None);
+
+        let limited = QueryContext::new().max_scanned_rows(3);
+        assert!(matches!(
+            db.query_cf_with_context::<Room>("rooms", query, &limited),
+            Err(KvStoreError::QueryAborted(AbortReason::RowLimitReached))
+        ));
+
+        // Partial results keep what matched before the limit
+        let partial = db
+            .query_cf_with_context::<Room>("rooms", query, &limited.clone().allow_partial())
+            .unwrap();
+        let keys: Vec<&str> = partial.items.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["room:0", "room:2"]);
+        assert_eq!(partial.aborted, Some(AbortReason::RowLimitReached));
+
+        let token = CancellationToken::new();
+        let cancellable = QueryContext::new().cancellation(token.clone());
+        assert!(db
+            .query_cf_with_context::<Room>("rooms", query, &cancellable)
+            .is_ok());
+        token.cancel();
+        assert!(matches!(
+            db.query_cf_with_context::<Room>("rooms", query, &cancellable),
+            Err(KvStoreError::QueryAborted(AbortReason::Cancelled))
+        ));
+
+        let expired = QueryContext::new().timeout(std::time::Duration::ZERO);
+        assert!(matches!(
+            db.query_cf_with_context::<Room>("rooms", query, &expired),
+            Err(KvStoreError::QueryAborted(AbortReason::DeadlineExceeded))
+        ));
+
+        // Every query path honours the context
+        let options = QueryOptions::new().context(limited.clone());
+        assert!(matches!(
+            db.query_cf_with_options::<Room, Room>("rooms", query, &options),
+            Err(KvStoreError::QueryAborted(AbortReason::RowLimitReached))
+        ));
+        let keys = KeyFilter::prefix("room:");
+        assert!(matches!(
+            db.query_cf_by_keys_with_context::<Room>("rooms", &keys, query, &limited),
+            Err(KvStoreError::QueryAborted(AbortReason::RowLimitReached))
+        ));
+        assert!(matches!(
+            db.par_query_cf_with_context::<Room>("rooms", query, 2, &limited),
+            Err(KvStoreError::QueryAborted(AbortReason::RowLimitReached))
+        ));
+        let count = Aggregation::new().count("n").context(limited.clone());
+        assert!(matches!(
+            db.aggregate_cf::<serde_json::Value>("rooms", &count),
+            Err(KvStoreError::QueryAborted(AbortReason::RowLimitReached))
+        ));
+
+        // Index scans check the context before loading candidates
+        db.create_index::<Room>("rooms", "style", "$.style")
+            .unwrap();
+        assert!(matches!(
+            db.query_cf_with_context::<Room>("rooms", query, &cancellable),
+            Err(KvStoreError::QueryAborted(AbortReason::Cancelled))
+        ));
+    }
+
+    #[test]
+    fn test_filter_with_predicates() {
+        let (_temp_dir, db) = create_temp_db();
+        insert_lobbies(
+            &db,
+            &[
+                ("l1", "Team", 2, 4),
+                ("l2", "Solo", 1, 2),
+                ("l3", "Team", 3, 8),
+                ("l4", "Duo", 0, 6),
+            ],
+        );
+
+        let open = db
+            .filter_cf("lobbies", |key, lobby: &Lobby| {
+                key != "l1" && lobby.player_count < lobby.capacity
+            })
+            .unwrap();
+        let keys: Vec<&str> = open.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["l2", "l3", "l4"]);
+
+        let large_team: Predicate = field("style")
+            .eq("Team")
+            .and(field("capacity").gt(4))
+            .or(field("player_count").eq(0));
+        let matched = db
+            .filter_cf("lobbies", |_, lobby: &Lobby| large_team.matches(lobby))
+            .unwrap();
+        let keys: Vec<&str> = matched.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["l3", "l4"]);
+
+        let not_team = !field("style").eq("Team");
+        let lobby = Lobby {
+            style: "Solo".to_string(),
+            player_count: 0,
+            capacity: 2,
+        };
+        assert!(not_team.matches(&lobby));
+        assert!(!field("capacity").gt("1").matches(&lobby));
+        assert!(!field("missing").exists().matches(&lobby));
+    }
+
+    #[test]
+    fn test_filters_skip_decoding_non_matches() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        db.insert_cf("rooms", "r1", &room(1, "Team")).unwrap();
+        db.insert_cf("rooms", "r2", &room(2, "Solo")).unwrap();
+        // Would fail to decode as a Room, but never matches
+        db.insert_cf(
+            "rooms",
+            "r3",
+            &serde_json::json!({ "owner": "nobody", "style": 5, "extra": [1, { "a": null }] }),
+        )
+        .unwrap();
+        db.insert_cf("rooms", "r4", &room(4, "Team")).unwrap();
+
+        let teams = db
+            .query_cf_with_keys::<Room>("rooms", "$[?@.style == 'Team' && @.owner > 0]")
+            .unwrap();
+        let keys: Vec<&str> = teams.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["r1", "r4"]);
+        let parallel = db
+            .par_query_cf::<Room>("rooms", "$[?@.style == 'Solo']", 2)
+            .unwrap();
+        assert_eq!(parallel.len(), 1);
+        assert_eq!(parallel[0].key, "r2");
+
+        let solo = db
+            .filter_cf_where::<Room>("rooms", &field("style").eq("Solo"))
+            .unwrap();
+        assert_eq!(solo.len(), 1);
+        assert_eq!(solo[0].value, room(2, "Solo"));
+
+        // Matching values are still decoded into T
+        assert!(matches!(
+            db.query_cf_with_keys::<Room>("rooms", "$[?@.style == 5]"),
+            Err(KvStoreError::DeserializationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_filters_keep_defaulted_fields() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct RoomWithVisibility {
+            owner: u32,
+            style: String,
+            #[serde(default)]
+            private: bool,
+        }
+
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        // Stored before `private` existed; decoding fills it in as false
+        db.insert_cf("rooms", "r1", &room(1, "Team")).unwrap();
+        db.insert_cf("rooms", "r2", &room(2, "Solo")).unwrap();
+
+        let public = db
+            .query_cf_with_keys::<RoomWithVisibility>("rooms", "$[?@.private == false]")
+            .unwrap();
+        assert_eq!(public.len(), 2);
+        let public = db
+            .filter_cf_where::<RoomWithVisibility>("rooms", &field("private").eq(false))
+            .unwrap();
+        assert_eq!(public.len(), 2);
+        let team = db
+            .filter_cf_where::<RoomWithVisibility>(
+                "rooms",
+                &field("style").eq("Team").and(!field("private").eq(true)),
+            )
+            .unwrap();
+        assert_eq!(team.len(), 1);
+        assert_eq!(team[0].key, "r1");
+    }
+
+    #[test]
+    fn test_full_backups() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        db.insert_cf("rooms", "r1", &room(1, "Team")).unwrap();
+        db.insert(
+            "user",
+            &TestUser {
+                id: 1,
+                name: "a".to_string(),
+            },
+        )
+        .unwrap();
+
+        let backup_dir = TempDir::new().unwrap();
+        let mut backups = BackupStore::open(backup_dir.path()).unwrap();
+        let first = backups.create(&db).unwrap();
+        db.insert_cf("rooms", "r2", &room(2, "Solo")).unwrap();
+        let second = backups.create(&db).unwrap();
+        assert!(second.id > first.id);
+        assert_eq!(backups.list(), vec![first, second]);
+        backups.verify(first.id).unwrap();
+        backups.verify(second.id).unwrap();
+
+        // The first backup still only sees r1
+        let restore_dir = TempDir::new().unwrap();
+        backups.restore(Some(first.id), restore_dir.path()).unwrap();
+        {
+            let restored =
+                RocksDB::open_with_existing_cfs(&Options::default(), restore_dir.path()).unwrap();
+            let rooms = restored
+                .query_cf_with_keys::<Room>("rooms", "$[*]")
+                .unwrap();
+            assert_eq!(rooms.len(), 1);
+            assert_eq!(restored.get::<TestUser>("user").unwrap().id, 1);
+        }
+        // Restoring needs an empty target
+        assert!(backups.restore(None, restore_dir.path()).is_err());
+
+        backups.purge(1).unwrap();
+        assert_eq!(backups.list(), vec![second]);
+        let latest_dir = TempDir::new().unwrap();
+        backups.restore(None, latest_dir.path()).unwrap();
+        let restored =
+            RocksDB::open_with_existing_cfs(&Options::default(), latest_dir.path()).unwrap();
+        assert_eq!(
+            restored.get_cf::<Room>("rooms", "r2").unwrap(),
+            room(2, "Solo")
+        );
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        db.insert_cf("rooms", "r1", &room(1, "Team")).unwrap();
+
+        let checkpoint_dir = TempDir::new().unwrap();
+        let checkpoint_path = checkpoint_dir.path().join("checkpoint");
+        db.create_checkpoint(&checkpoint_path).unwrap();
+        db.insert_cf("rooms", "r2", &room(2, "Solo")).unwrap();
+
+        let checkpoint = RocksDB::open_read_only(&Options::default(), &checkpoint_path).unwrap();
+        let rooms = checkpoint
+            .query_cf_with_keys::<Room>("rooms", "$[*]")
+            .unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(
+            checkpoint.get_cf::<Room>("rooms", "r1").unwrap(),
+            room(1, "Team")
+        );
+        assert!(checkpoint
+            .insert_cf("rooms", "r3", &room(3, "Duo"))
+            .is_err());
+
+        // The checkpoint stays usable independently of the source
+        db.insert_cf("rooms", "r3", &room(3, "Duo")).unwrap();
+        assert!(checkpoint.get_cf::<Room>("rooms", "r3").is_err());
+
+        // An existing directory is rejected
+        assert!(db.create_checkpoint(&checkpoint_path).is_err());
+    }
+
+    #[test]
+    fn test_export_cfs() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        db.create_cf("archive").unwrap();
+        db.insert_cf("rooms", "r1", &room(1, "Team")).unwrap();
+        db.insert_cf("rooms", "r2", &room(2, "Solo")).unwrap();
+
+        // Empty column families write no SST file
+        let file_dir = TempDir::new().unwrap();
+        let empty_path = file_dir.path().join("archive.sst");
+        let empty = db
+            .create_backup("archive", empty_path.to_str().unwrap())
+            .unwrap();
+        assert_eq!(empty.entries, 0);
+        assert!(!empty_path.exists());
+
+        let export_dir = TempDir::new().unwrap();
+        let manifest = db
+            .export_cfs(&["rooms", "archive"], export_dir.path())
+            .unwrap();
+        assert_eq!(manifest.get("rooms").unwrap().manifest.entries, 2);
+        assert_eq!(manifest.get("archive").unwrap().file, None);
+        assert_eq!(ExportManifest::read(export_dir.path()).unwrap(), manifest);
+        // Exporting twice into the same directory is rejected
+        assert!(db.export_cfs(&["rooms"], export_dir.path()).is_err());
+
+        let (_other_dir, other) = create_temp_db();
+        other.import_cfs(export_dir.path()).unwrap();
+        assert!(other.cf_exists("archive"));
+        assert_eq!(
+            other.get_cf::<Room>("rooms", "r2").unwrap(),
+            room(2, "Solo")
+        );
+        assert_eq!(
+            other
+                .query_cf_with_keys::<Room>("rooms", "$[*]")
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_backup_manifests() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        db.create_cf("lobbies").unwrap();
+        db.insert_cf("rooms", "r1", &room(1, "Team")).unwrap();
+        db.insert_cf("rooms", "r2", &room(2, "Solo")).unwrap();
+
+        let backup_dir = TempDir::new().unwrap();
+        let sst = backup_dir.path().join("rooms.sst");
+        let path = sst.to_str().unwrap();
+        let manifest = db.create_backup("rooms", path).unwrap();
+        assert_eq!(manifest.cf, "rooms");
+        assert_eq!(manifest.entries, 2);
+        assert_eq!(
+            manifest.first_key.as_deref(),
+            Some(hex::encode("r1").as_str())
+        );
+        assert_eq!(
+            manifest.last_key.as_deref(),
+            Some(hex::encode("r2").as_str())
+        );
+        assert_eq!(manifest.size, std::fs::metadata(&sst).unwrap().len());
+        assert_eq!(
+            BackupManifest::read(BackupManifest::path_for(&sst)).unwrap(),
+            manifest
+        );
+
+        // A backup is only restored into the column family it was taken from
+        assert!(matches!(
+            db.restore_backup("lobbies", path),
+            Err(KvStoreError::InvalidBackup(_))
+        ));
+
+        let (_other_dir, other) = create_temp_db();
+        other.create_cf("rooms").unwrap();
+        other.restore_backup("rooms", path).unwrap();
+        assert_eq!(
+            other.get_cf::<Room>("rooms", "r1").unwrap(),
+            room(1, "Team")
+        );
+
+        // Truncated and corrupted files are refused before ingesting
+        let bytes = std::fs::read(&sst).unwrap();
+        std::fs::write(&sst, &bytes[..bytes.len() / 2]).unwrap();
+        let (_third_dir, third) = create_temp_db();
+        third.create_cf("rooms").unwrap();
+        assert!(matches!(
+            third.restore_backup("rooms", path),
+            Err(KvStoreError::InvalidBackup(_))
+        ));
+        let mut corrupted = bytes.clone();
+        corrupted[bytes.len() / 2] ^= 0xff;
+        std::fs::write(&sst, &corrupted).unwrap();
+        assert!(matches!(
+            third.restore_backup("rooms", path),
+            Err(KvStoreError::InvalidBackup(_))
+        ));
+        assert!(third.get_cf::<Room>("rooms", "r1").is_err());
+
+        // So are manifests whose entries or keys don't match the file
+        std::fs::write(&sst, &bytes).unwrap();
+        assert_eq!(manifest.checksum, crc32fast::hash(&bytes));
+        for tampered in [
+            BackupManifest {
+                entries: 3,
+                ..manifest.clone()
+            },
+            BackupManifest {
+                last_key: Some(hex::encode("r3")),
+                ..manifest.clone()
+            },
+        ] {
+            std::fs::write(
+                BackupManifest::path_for(&sst),
+                serde_json::to_vec(&tampered).unwrap(),
+            )
+            .unwrap();
+            assert!(matches!(
+                third.restore_backup("rooms", path),
+                Err(KvStoreError::InvalidBackup(_))
+            ));
+        }
+        assert!(third.get_cf::<Room>("rooms", "r1").is_err());
+        assert!(!third.cf_exists("__backup_check__"));
+
+        // Backups without a manifest are refused too
+        std::fs::remove_file(BackupManifest::path_for(&sst)).unwrap();
+        assert!(third.restore_backup("rooms", path).is_err());
+    }
+
+    #[test]
+    fn test_restore_modes() {
+        let (_temp_dir, db) = create_temp_db();
+        db.create_cf("rooms").unwrap();
+        db.create_cf("lobbies").unwrap();
+        db.insert_cf("rooms", "r1", &room(1, "Team")).unwrap();
+        db.insert_cf("rooms", "r2", &room(2, "Solo")).unwrap();
+
+        let backup_dir = TempDir::new().unwrap();
+        let sst = backup_dir.path().join("rooms.sst");
+        let path = sst.to_str().unwrap();
+        db.create_backup("rooms", path).unwrap();
+
+        db.insert_cf("rooms", "r1", &room(9, "Changed")).unwrap();
+        db.insert_cf("rooms", "r3", &room(3, "Duo")).unwrap();
+        let count = |cf: &str| db.query_cf_with_keys::<Room>(cf, "$[*]").unwrap().len();
+
+        // Without a global seqno the overlapping keys cannot be ingested
+        let strict = RestoreOptions::new().allow_global_seqno(false);
+        assert!(db.restore_backup_with("rooms", path, &strict).is_err());
+
+        // Merging overwrites r1 but keeps r3
+        db.restore_backup("rooms", path).unwrap();
+        assert_eq!(db.get_cf::<Room>("rooms", "r1").unwrap(), room(1, "Team"));
+        assert_eq!(count("rooms"), 3);
+
+        let replace = RestoreOptions::new().mode(RestoreMode::Replace);
+        db.restore_backup_with("rooms", path, &replace).unwrap();
+        assert_eq!(count("rooms"), 2);
+        assert!(db.get_cf::<Room>("rooms", "r3").is_err());
+        assert!(matches!(
+            db.restore_backup_with("lobbies", path, &replace),
+            Err(KvStoreError::InvalidBackup(_))
+        ));
+        db.insert("user", &room(4, "Solo")).unwrap();
+        let default_sst = backup_dir.path().join("default.sst");
+        let default_path = default_sst.to_str().unwrap();
+        db.create_backup("default", default_path).unwrap();
+        assert!(matches!(
+            db.restore_backup_with("default", default_path, &replace),
+            Err(KvStoreError::InvalidColumnFamily(_))
+        ));
+        assert_eq!(db.get::<Room>("user").unwrap(), room(4, "Solo"));
+
+        let into_new = RestoreOptions::new().mode(RestoreMode::IntoNew);
+        db.restore_backup_with("rooms_copy", path, &into_new)
+            .unwrap();
+        assert_eq!(count("rooms_copy"), 2);
+        assert!(matches!(
+            db.restore_backup_with("rooms_copy", path, &into_new),
+            Err(KvStoreError::InvalidColumnFamily(_))
+        ));
+
+        // Ingesting would leave indexes stale, so indexed CFs are refused
+        db.create_index::<Room>("rooms", "style", "$.style")
+            .unwrap();
+        for mode in [RestoreMode::Merge, RestoreMode::Replace] {
+            assert!(matches!(
+                db.restore_backup_with("rooms", path, &RestoreOptions::new().mode(mode)),
+                Err(KvStoreError::InvalidColumnFamily(_))
+            ));
+        }
+        assert_eq!(
+            db.find_by_index_cf::<Room, _>("rooms", "style", &"Team")
+                .unwrap()
+                .len(),
+            1
+        );
+        let export_dir = TempDir::new().unwrap();
+        db.export_cfs(&["rooms"], export_dir.path()).unwrap();
+        assert!(matches!(
+            db.import_cfs(export_dir.path()),
+            Err(KvStoreError::InvalidColumnFamily(_))
+        ));
+        db.drop_index("rooms", "style").unwrap();
+        db.restore_backup("rooms", path).unwrap();
+
+        // Moving consumes the backup file
+        let moved = into_new.move_files(true);
+        db.restore_backup_with("rooms_moved", path, &moved).unwrap();
+        assert_eq!(count("rooms_moved"), 2);
+        assert!(!sst.exists());
+    }
 }